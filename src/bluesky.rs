@@ -0,0 +1,595 @@
+use crate::storage::{append_line, open_lines};
+use crate::twitter::text::URL_LENGTH;
+use crate::twitter::types::{Postable, TwitterBuilder, TwitterClient};
+use crate::types::BlueskyConfig;
+use async_trait::async_trait;
+use mime_guess::{mime, Mime};
+use regex::Regex;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::sync::Mutex;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Maximum number of graphemes of the text of a post
+pub const MAX_POST_LENGTH: usize = 300;
+
+/// Maximum size of an image, in bytes
+const MAX_BLOB_SIZE: usize = 1_000_000;
+
+pub(crate) const BLOBS_FILE: &str = "blobs";
+
+const POST_COLLECTION: &str = "app.bsky.feed.post";
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    access_jwt: String,
+    did: String,
+}
+
+/// An uploaded image, by the id given to the uploader
+#[derive(Serialize, Deserialize)]
+struct BlobEntry {
+    id: u64,
+    blob: Value,
+}
+
+struct State {
+    session: Option<Session>,
+    blobs: HashMap<u64, Value>,
+    blobs_file: File,
+    /// The DID of the handles mentioned, `None` for the ones that do not exist
+    dids: HashMap<String, Option<String>>,
+}
+
+/// Posts to a Bluesky account through the XRPC API of its PDS
+///
+/// Bluesky identifies uploaded images by the whole blob reference, not by a number. The
+/// references are kept in the `blobs` file of the data dir under a hash of their CID, which is
+/// the media id the other stages see, so resumed posts find them. Posts are identified by their
+/// `at://` URI. Clones share the session.
+#[derive(Clone)]
+pub struct BlueskyClient {
+    builder: TwitterBuilder,
+    http: Client,
+    service: String,
+    identifier: String,
+    app_password: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl BlueskyClient {
+    pub async fn open(config: &BlueskyConfig, data_dir: &str) -> Self {
+        let (blobs_file, entries) = open_lines::<BlobEntry>(data_dir, BLOBS_FILE).await;
+        BlueskyClient {
+            builder: TwitterBuilder::new(),
+            http: Client::new(),
+            service: config.service.trim_end_matches('/').to_string(),
+            identifier: config.identifier.clone(),
+            app_password: config.app_password.clone(),
+            state: Arc::new(Mutex::new(State {
+                session: None,
+                blobs: entries.into_iter().map(|e| (e.id, e.blob)).collect(),
+                blobs_file,
+                dids: HashMap::new(),
+            })),
+        }
+    }
+
+    fn xrpc(&self, method: reqwest::Method, nsid: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}/xrpc/{}", self.service, nsid))
+    }
+
+    /// The current session, logging in with the app password if there is none
+    async fn session(&self) -> Result<Session, Box<dyn Error + Send + Sync>> {
+        let mut state = self.state.lock().await;
+        if let Some(session) = &state.session {
+            return Ok(session.clone());
+        }
+        let response = self
+            .xrpc(reqwest::Method::POST, "com.atproto.server.createSession")
+            .json(&json!({"identifier": self.identifier, "password": self.app_password}))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Bluesky login failed with {}: {}", status, body).into());
+        }
+        let session: Session = response.json().await?;
+        state.session = Some(session.clone());
+        Ok(session)
+    }
+
+    /// Sends an authenticated request, logging in again once if the session expired
+    async fn call<F>(&self, request: F) -> Result<Value, Box<dyn Error + Send + Sync>>
+    where
+        F: Fn(&Session) -> RequestBuilder,
+    {
+        for _ in 0..2 {
+            let session = self.session().await?;
+            let response = request(&session)
+                .bearer_auth(&session.access_jwt)
+                .send()
+                .await?;
+            let status = response.status();
+            let body = response.text().await?;
+            if status.is_success() {
+                return Ok(serde_json::from_str(&body).unwrap_or(Value::Null));
+            }
+            if body.contains("ExpiredToken") {
+                self.state.lock().await.session = None;
+                continue;
+            }
+            return Err(format!("Bluesky request failed with {}: {}", status, body).into());
+        }
+        Err("Bluesky session expired".into())
+    }
+
+    /// The DID of the handle, if it exists
+    async fn resolve_handle(&self, handle: &str) -> Option<String> {
+        if let Some(did) = self.state.lock().await.dids.get(handle) {
+            return did.clone();
+        }
+        let did = self
+            .call(|_| {
+                self.xrpc(reqwest::Method::GET, "com.atproto.identity.resolveHandle")
+                    .query(&[("handle", handle)])
+            })
+            .await
+            .ok()
+            .and_then(|r| r["did"].as_str().map(String::from));
+        if did.is_none() {
+            log::info!("Bluesky handle {} not found, it is not linked", handle);
+        }
+        self.state
+            .lock()
+            .await
+            .dids
+            .insert(handle.to_string(), did.clone());
+        did
+    }
+
+    /// The strong reference, i.e. URI and CID, of a post and the record itself
+    async fn get_post(&self, uri: &str) -> Result<(Value, Value), Box<dyn Error + Send + Sync>> {
+        let (repo, rkey) = parse_uri(uri)?;
+        let record = self
+            .call(|_| {
+                self.xrpc(reqwest::Method::GET, "com.atproto.repo.getRecord")
+                    .query(&[
+                        ("repo", repo),
+                        ("collection", POST_COLLECTION),
+                        ("rkey", rkey),
+                    ])
+            })
+            .await?;
+        let reference = json!({"uri": record["uri"], "cid": record["cid"]});
+        Ok((reference, record["value"].clone()))
+    }
+
+    /// The embed of the images and of the quoted post, if any
+    async fn embed(&self) -> Result<Option<Value>, Box<dyn Error + Send + Sync>> {
+        let images = {
+            let state = self.state.lock().await;
+            self.builder
+                .media_ids()
+                .iter()
+                .map(|id| {
+                    let blob = state
+                        .blobs
+                        .get(id)
+                        .ok_or_else(|| format!("Unknown Bluesky blob {}", id))?;
+                    Ok(json!({"alt": "", "image": blob}))
+                })
+                .collect::<Result<Vec<_>, String>>()?
+        };
+        let images = (!images.is_empty())
+            .then(|| json!({"$type": "app.bsky.embed.images", "images": images}));
+        // Posts mirrored to other kinds of destinations can not be quoted
+        let quote = match self.builder.quote().filter(|uri| uri.starts_with("at://")) {
+            Some(uri) => Some(self.get_post(uri).await?.0),
+            None => None,
+        };
+        Ok(match (images, quote) {
+            (images, None) => images,
+            (None, Some(quote)) => Some(json!({"$type": "app.bsky.embed.record", "record": quote})),
+            (Some(images), Some(quote)) => Some(json!({
+                "$type": "app.bsky.embed.recordWithMedia",
+                "record": {"$type": "app.bsky.embed.record", "record": quote},
+                "media": images,
+            })),
+        })
+    }
+}
+
+/// The repo and the record key of an `at://` URI of a post
+fn parse_uri(uri: &str) -> Result<(&str, &str), Box<dyn Error + Send + Sync>> {
+    let parts: Vec<&str> = uri
+        .strip_prefix("at://")
+        .map(|path| path.split('/').collect())
+        .unwrap_or_default();
+    match parts.as_slice() {
+        [repo, POST_COLLECTION, rkey] => Ok((repo, rkey)),
+        _ => Err(format!("Invalid Bluesky post URI {}", uri).into()),
+    }
+}
+
+/// The links, mentions and hashtags of a text
+static TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?P<url>(?:https?://|www\.)\S+)|(?P<mention>@[a-zA-Z0-9][a-zA-Z0-9-]*(?:\.[a-zA-Z0-9-]+)+)|(?P<tag>#\w+)",
+    )
+    .unwrap()
+});
+
+/// The handles mentioned in the text
+fn mentions(text: &str) -> Vec<String> {
+    TOKEN
+        .captures_iter(text)
+        .filter_map(|c| c.name("mention"))
+        .map(|m| m.as_str()[1..].trim_end_matches('.').to_lowercase())
+        .collect()
+}
+
+/// The text as posted, with long URLs shortened, and the facets of its links, mentions and
+/// hashtags. Facets point to UTF-8 byte offsets of the text as posted
+fn rich_text(text: &str, dids: &HashMap<String, Option<String>>) -> (String, Vec<Value>) {
+    let mut posted = String::new();
+    let mut facets = vec![];
+    let mut last = 0;
+    for captures in TOKEN.captures_iter(text) {
+        let token = captures.get(0).unwrap();
+        let at_word_start = token.start() == 0
+            || text[..token.start()].ends_with(|c: char| c.is_whitespace() || c == '(');
+        if !at_word_start {
+            continue;
+        }
+        let (shown, feature) = if let Some(url) = captures.name("url") {
+            let url = url
+                .as_str()
+                .trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+            let uri = if url.starts_with("www.") {
+                format!("https://{}", url)
+            } else {
+                url.to_string()
+            };
+            let feature = json!({"$type": "app.bsky.richtext.facet#link", "uri": uri});
+            (url, Some(feature))
+        } else if let Some(mention) = captures.name("mention") {
+            let handle = mention.as_str().trim_end_matches('.');
+            let did = dids.get(&handle[1..].to_lowercase()).cloned().flatten();
+            let feature =
+                did.map(|did| json!({"$type": "app.bsky.richtext.facet#mention", "did": did}));
+            (handle, feature)
+        } else {
+            let tag = token.as_str();
+            let feature = (!tag[1..].chars().all(|c| c.is_ascii_digit()))
+                .then(|| json!({"$type": "app.bsky.richtext.facet#tag", "tag": &tag[1..]}));
+            (tag, feature)
+        };
+
+        posted.push_str(&text[last..token.start()]);
+        let start = posted.len();
+        match &feature {
+            Some(f) if f["uri"].is_string() => posted.push_str(&shorten(shown)),
+            _ => posted.push_str(shown),
+        }
+        if let Some(feature) = feature {
+            facets.push(json!({
+                "index": {"byteStart": start, "byteEnd": posted.len()},
+                "features": [feature],
+            }));
+        }
+        last = token.start() + shown.len();
+    }
+    posted.push_str(&text[last..]);
+    (posted, facets)
+}
+
+/// URLs are shown without scheme and cut to the length Twitter counts for them, so the text
+/// split for Twitter fits as well
+fn shorten(url: &str) -> String {
+    let url = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let graphemes: Vec<&str> = url.graphemes(true).collect();
+    if graphemes.len() <= URL_LENGTH {
+        return url.to_string();
+    }
+    format!("{}...", graphemes[..URL_LENGTH - 3].concat())
+}
+
+#[async_trait]
+impl Postable for BlueskyClient {
+    /// Uploads an image, Bluesky posts do not take other media
+    async fn upload_media(
+        &mut self,
+        file: &Path,
+        media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        if media_type.type_() != mime::IMAGE {
+            return Err(format!("media type unrecognized by Bluesky: {}", media_type).into());
+        }
+        let content = tokio::fs::read(file).await?;
+        if content.len() > MAX_BLOB_SIZE {
+            return Err(format!(
+                "Blob is too large: {} bytes, the maximum is {}",
+                content.len(),
+                MAX_BLOB_SIZE
+            )
+            .into());
+        }
+        let response = self
+            .call(|_| {
+                self.xrpc(reqwest::Method::POST, "com.atproto.repo.uploadBlob")
+                    .header(reqwest::header::CONTENT_TYPE, media_type.as_ref())
+                    .body(content.clone())
+            })
+            .await?;
+        let blob = response["blob"].clone();
+        let cid = blob["ref"]["$link"]
+            .as_str()
+            .ok_or_else(|| format!("Invalid Bluesky blob {}", blob))?;
+        let id = crate::util::stable_hash(cid.as_bytes());
+
+        let mut state = self.state.lock().await;
+        append_line(
+            &mut state.blobs_file,
+            &BlobEntry {
+                id,
+                blob: blob.clone(),
+            },
+        )
+        .await;
+        state.blobs.insert(id, blob);
+        Ok(id)
+    }
+
+    async fn send(&mut self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let text = self.builder.text();
+        for handle in mentions(&text) {
+            self.resolve_handle(&handle).await;
+        }
+        let (text, facets) = rich_text(&text, &self.state.lock().await.dids);
+        let length = text.graphemes(true).count();
+        if length > MAX_POST_LENGTH {
+            return Err(format!(
+                "Post is too long: {} characters, the maximum is {}",
+                length, MAX_POST_LENGTH
+            )
+            .into());
+        }
+
+        let mut record = json!({
+            "$type": POST_COLLECTION,
+            "text": text,
+            "createdAt": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        });
+        if !facets.is_empty() {
+            record["facets"] = json!(facets);
+        }
+        if let Some(parent_uri) = self.builder.reply_to() {
+            let (parent, value) = self.get_post(parent_uri).await?;
+            let root = match value["reply"]["root"].is_object() {
+                true => value["reply"]["root"].clone(),
+                false => parent.clone(),
+            };
+            record["reply"] = json!({"root": root, "parent": parent});
+        }
+        if let Some(embed) = self.embed().await? {
+            record["embed"] = embed;
+        }
+
+        let response = self
+            .call(|session| {
+                self.xrpc(reqwest::Method::POST, "com.atproto.repo.createRecord")
+                    .json(&json!({
+                        "repo": session.did,
+                        "collection": POST_COLLECTION,
+                        "record": record,
+                    }))
+            })
+            .await?;
+        response["uri"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| format!("Invalid Bluesky response {}", response).into())
+    }
+
+    async fn delete(&mut self, tweet_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (repo, rkey) = parse_uri(tweet_id)?;
+        self.call(|_| {
+            self.xrpc(reqwest::Method::POST, "com.atproto.repo.deleteRecord")
+                .json(&json!({"repo": repo, "collection": POST_COLLECTION, "rkey": rkey}))
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+impl TwitterClient for BlueskyClient {
+    fn new_builder(&mut self) -> &mut TwitterBuilder {
+        self.builder = TwitterBuilder::new();
+        &mut self.builder
+    }
+
+    fn max_length(&self) -> usize {
+        MAX_POST_LENGTH
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+    use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const DID: &str = "did:plc:mirror";
+
+    #[test]
+    fn test_rich_text() {
+        let mut dids = HashMap::new();
+        dids.insert(
+            "jane.bsky.social".to_string(),
+            Some("did:plc:jane".to_string()),
+        );
+        dids.insert("ghost.example".to_string(), None);
+        let (text, facets) = rich_text(
+            "🎉 @jane.bsky.social (https://example.com/a/very/long/path/to/a/page). #News #1 @ghost.example",
+            &dids,
+        );
+        assert_eq!(
+            text,
+            "🎉 @jane.bsky.social (example.com/a/very/l...). #News #1 @ghost.example"
+        );
+        assert_eq!(
+            facets,
+            vec![
+                json!({"index": {"byteStart": 5, "byteEnd": 22}, "features": [
+                    {"$type": "app.bsky.richtext.facet#mention", "did": "did:plc:jane"}]}),
+                json!({"index": {"byteStart": 24, "byteEnd": 47}, "features": [
+                    {"$type": "app.bsky.richtext.facet#link",
+                     "uri": "https://example.com/a/very/long/path/to/a/page"}]}),
+                json!({"index": {"byteStart": 50, "byteEnd": 55}, "features": [
+                    {"$type": "app.bsky.richtext.facet#tag", "tag": "News"}]}),
+            ]
+        );
+        assert_eq!(mentions(&text), vec!["jane.bsky.social", "ghost.example"]);
+    }
+
+    /// A stand-in PDS, expecting the mirror account to log in the number of times given
+    async fn pds(logins: u64) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.server.createSession"))
+            .and(body_partial_json(
+                json!({"identifier": "mirror.bsky.social"}),
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"accessJwt": "jwt", "did": DID})),
+            )
+            .expect(logins)
+            .mount(&server)
+            .await;
+        server
+    }
+
+    /// A client of the PDS, with the data dir it keeps its state in
+    async fn client(server: &MockServer, name: &str) -> (BlueskyClient, TempDir) {
+        let dir = TempDir::new(&format!("bluesky-{}", name));
+        let config = BlueskyConfig {
+            identifier: "mirror.bsky.social".to_string(),
+            app_password: "app-password".to_string(),
+            service: server.uri(),
+        };
+        (BlueskyClient::open(&config, dir.path()).await, dir)
+    }
+
+    #[tokio::test]
+    async fn test_thread_with_image() {
+        let server = pds(1).await;
+        let blob = json!({"$type": "blob", "ref": {"$link": "bafkimage"},
+                          "mimeType": "image/png", "size": 5});
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.uploadBlob"))
+            .and(header("Authorization", "Bearer jwt"))
+            .and(header("Content-Type", "image/png"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"blob": blob})))
+            .mount(&server)
+            .await;
+        let root =
+            json!({"uri": format!("at://{}/{}/root", DID, POST_COLLECTION), "cid": "bafyroot"});
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .and(body_partial_json(json!({"repo": DID, "record": {
+                "text": "First",
+                "embed": {"$type": "app.bsky.embed.images", "images": [{"alt": "", "image": blob}]},
+            }})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&root))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.repo.getRecord"))
+            .and(query_param("rkey", "root"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": root["uri"], "cid": root["cid"], "value": {"text": "First"},
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .and(body_partial_json(json!({"record": {
+                "text": "Second",
+                "reply": {"root": root, "parent": root},
+            }})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": format!("at://{}/{}/second", DID, POST_COLLECTION), "cid": "bafysecond",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (mut client, dir) = client(&server, "thread").await;
+        let file = dir.join("image.png");
+        tokio::fs::write(&file, b"image").await.unwrap();
+        let image = client.upload_media(&file, &mime::IMAGE_PNG).await.unwrap();
+        let video = client
+            .upload_media(&file, &"video/mp4".parse().unwrap())
+            .await;
+        assert!(!crate::twitter::types::is_retryable(&*video.unwrap_err()));
+
+        let builder = client.new_builder();
+        builder.set_text("First".to_string());
+        builder.add_media(image);
+        let first = client.send().await.unwrap();
+        assert_eq!(first, root["uri"]);
+
+        let builder = client.new_builder();
+        builder.set_text("Second".to_string());
+        builder.set_reply_to(Some(first));
+        assert!(client.send().await.unwrap().ends_with("/second"));
+    }
+
+    #[tokio::test]
+    async fn test_expired_session_and_delete() {
+        let server = pds(2).await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+            .respond_with(
+                ResponseTemplate::new(400).set_body_json(
+                    json!({"error": "ExpiredToken", "message": "Token has expired"}),
+                ),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+            .and(body_partial_json(json!({"repo": DID, "rkey": "post"})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (mut client, _dir) = client(&server, "delete").await;
+        client
+            .delete(&format!("at://{}/{}/post", DID, POST_COLLECTION))
+            .await
+            .unwrap();
+        assert!(client.delete("https://bsky.app/post").await.is_err());
+
+        client.new_builder().set_text("a".repeat(301));
+        let error = client.send().await.unwrap_err();
+        assert!(!crate::twitter::types::is_retryable(&*error));
+    }
+}
@@ -0,0 +1,198 @@
+#[cfg(feature = "sqlite")]
+use crate::sqlite::Database;
+use crate::storage::{append_line, open_lines, Storage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::sync::Mutex;
+
+pub(crate) const DEAD_LETTER_FILE: &str = "dead_letters";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadLetterStatus {
+    /// Gave up, waiting to be inspected
+    Failed,
+    /// Will be mirrored again on the next run
+    Requeued,
+    /// Mirrored after being requeued
+    Resolved,
+}
+
+/// A Telegram post that could not be mirrored after all the retries
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub tg_id: i32,
+    pub chat: String,
+    /// The stage that gave up, e.g. "upload"
+    pub stage: String,
+    pub error: String,
+    pub timestamp: u64,
+    pub status: DeadLetterStatus,
+}
+
+/// Durable queue of the posts that failed, kept in the `dead_letters` file in the data dir
+///
+/// Like the [`MappingStore`](crate::mapping::MappingStore), entries are appended as JSON lines
+/// and the latest entry of a Telegram message wins, or kept in the `dead_letters` table with the
+/// SQLite storage. Clones share the same state.
+#[derive(Clone)]
+pub struct DeadLetters {
+    chat: String,
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    backend: Backend,
+    entries: BTreeMap<i32, DeadLetter>,
+}
+
+enum Backend {
+    File(File),
+    /// Not saved, for a dry run
+    Memory,
+    #[cfg(feature = "sqlite")]
+    Sqlite(Database),
+}
+
+impl DeadLetters {
+    pub async fn open(storage: &Storage, data_dir: &str, chat: &str) -> DeadLetters {
+        let (backend, letters) = match storage.source() {
+            Storage::Files => {
+                let (file, letters) = open_lines::<DeadLetter>(data_dir, DEAD_LETTER_FILE).await;
+                (Backend::File(file), letters)
+            }
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(database) => (
+                Backend::Sqlite(database.clone()),
+                database.dead_letters(chat),
+            ),
+            Storage::DryRun { .. } => unreachable!("A dry run wraps the storage it reads from"),
+        };
+        let backend = if storage.is_dry_run() {
+            Backend::Memory
+        } else {
+            backend
+        };
+        let entries = letters
+            .into_iter()
+            .map(|letter| (letter.tg_id, letter))
+            .collect();
+
+        DeadLetters {
+            chat: chat.to_string(),
+            inner: Arc::new(Mutex::new(Inner { backend, entries })),
+        }
+    }
+
+    /// Records that a stage gave up on a Telegram post
+    pub async fn add(&self, tg_id: i32, stage: &str, error: &(dyn std::fmt::Display + Sync)) {
+        log::error!(
+            "[{}] Giving up on telegram post {}, moved to the dead letters: {}",
+            stage,
+            tg_id,
+            error
+        );
+        self.write(DeadLetter {
+            tg_id,
+            chat: self.chat.clone(),
+            stage: stage.to_string(),
+            error: error.to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System time")
+                .as_secs(),
+            status: DeadLetterStatus::Failed,
+        })
+        .await;
+    }
+
+    /// Flags the failed posts, or only the given one, to be mirrored again on the next run.
+    /// Returns the number of posts requeued
+    pub async fn requeue(&self, tg_id: Option<i32>) -> usize {
+        let failed: Vec<DeadLetter> = self
+            .with_status(DeadLetterStatus::Failed)
+            .await
+            .into_iter()
+            .filter(|letter| tg_id.map(|id| id == letter.tg_id).unwrap_or(true))
+            .collect();
+        let count = failed.len();
+        for letter in failed {
+            self.write(DeadLetter {
+                status: DeadLetterStatus::Requeued,
+                ..letter
+            })
+            .await;
+        }
+        count
+    }
+
+    /// Records that a requeued post was finally mirrored
+    pub async fn resolve(&self, tg_id: i32) {
+        let letter = self.inner.lock().await.entries.get(&tg_id).cloned();
+        if let Some(letter) = letter.filter(|l| l.status != DeadLetterStatus::Resolved) {
+            self.write(DeadLetter {
+                status: DeadLetterStatus::Resolved,
+                ..letter
+            })
+            .await;
+        }
+    }
+
+    /// The entries with the given status, oldest Telegram message first
+    pub async fn with_status(&self, status: DeadLetterStatus) -> Vec<DeadLetter> {
+        self.inner
+            .lock()
+            .await
+            .entries
+            .values()
+            .filter(|letter| letter.status == status)
+            .cloned()
+            .collect()
+    }
+
+    async fn write(&self, letter: DeadLetter) {
+        let mut inner = self.inner.lock().await;
+        match &mut inner.backend {
+            Backend::File(file) => append_line(file, &letter).await,
+            Backend::Memory => {}
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(database) => database.save_dead_letter(&letter),
+        }
+        inner.entries.insert(letter.tg_id, letter);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[tokio::test]
+    async fn test_requeue_and_resolve() {
+        let dir = TempDir::new("dead-letters");
+
+        let letters = DeadLetters::open(&Storage::Files, dir.path(), "chat").await;
+        letters.add(1, "upload", &"timeout").await;
+        letters.add(2, "post", &"timeout").await;
+        assert_eq!(letters.requeue(Some(2)).await, 1);
+        letters.resolve(2).await;
+
+        // The state is read back from the file
+        let letters = DeadLetters::open(&Storage::Files, dir.path(), "chat").await;
+        let failed = letters.with_status(DeadLetterStatus::Failed).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!((failed[0].tg_id, failed[0].stage.as_str()), (1, "upload"));
+        assert_eq!(
+            letters.with_status(DeadLetterStatus::Resolved).await[0].tg_id,
+            2
+        );
+        assert_eq!(letters.requeue(None).await, 1);
+        assert!(letters
+            .with_status(DeadLetterStatus::Failed)
+            .await
+            .is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::bluesky::BlueskyClient;
+use crate::dry_run::DryRunClient;
+use crate::mastodon::MastodonClient;
+use crate::twitter::critter_client::CritterClient;
+use crate::twitter::rate_limit::RateLimiter;
+use crate::twitter::types::{Postable, TwitterBuilder, TwitterClient};
+use crate::types::{DestinationConfig, Target};
+use async_trait::async_trait;
+use mime_guess::Mime;
+use std::error::Error;
+use std::path::Path;
+
+/// The account a route mirrors to
+#[derive(Clone)]
+pub enum Destination {
+    Twitter(CritterClient),
+    Mastodon(MastodonClient),
+    Bluesky(BlueskyClient),
+    /// Stands for any of them with `--dry-run`
+    DryRun(DryRunClient),
+}
+
+impl Destination {
+    /// The client of a destination of a route
+    pub async fn open(target: &Target) -> Self {
+        match &target.config {
+            DestinationConfig::Twitter(twitter) => {
+                let limits = RateLimiter::open(&target.data_dir, twitter.daily_tweets).await;
+                Destination::Twitter(CritterClient::new(twitter, limits))
+            }
+            DestinationConfig::Mastodon(mastodon) => {
+                Destination::Mastodon(MastodonClient::new(mastodon))
+            }
+            DestinationConfig::Bluesky(bluesky) => {
+                Destination::Bluesky(BlueskyClient::open(bluesky, &target.data_dir).await)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Postable for Destination {
+    async fn upload_media(
+        &mut self,
+        file: &Path,
+        media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        match self {
+            Destination::Twitter(client) => client.upload_media(file, media_type).await,
+            Destination::Mastodon(client) => client.upload_media(file, media_type).await,
+            Destination::Bluesky(client) => client.upload_media(file, media_type).await,
+            Destination::DryRun(client) => client.upload_media(file, media_type).await,
+        }
+    }
+
+    async fn send(&mut self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        match self {
+            Destination::Twitter(client) => client.send().await,
+            Destination::Mastodon(client) => client.send().await,
+            Destination::Bluesky(client) => client.send().await,
+            Destination::DryRun(client) => client.send().await,
+        }
+    }
+
+    async fn delete(&mut self, tweet_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self {
+            Destination::Twitter(client) => client.delete(tweet_id).await,
+            Destination::Mastodon(client) => client.delete(tweet_id).await,
+            Destination::Bluesky(client) => client.delete(tweet_id).await,
+            Destination::DryRun(client) => client.delete(tweet_id).await,
+        }
+    }
+}
+
+impl TwitterClient for Destination {
+    fn new_builder(&mut self) -> &mut TwitterBuilder {
+        match self {
+            Destination::Twitter(client) => client.new_builder(),
+            Destination::Mastodon(client) => client.new_builder(),
+            Destination::Bluesky(client) => client.new_builder(),
+            Destination::DryRun(client) => client.new_builder(),
+        }
+    }
+
+    fn max_length(&self) -> usize {
+        match self {
+            Destination::Twitter(client) => client.max_length(),
+            Destination::Mastodon(client) => client.max_length(),
+            Destination::Bluesky(client) => client.max_length(),
+            Destination::DryRun(client) => client.max_length(),
+        }
+    }
+
+    fn length(&self, text: &str) -> usize {
+        match self {
+            Destination::Twitter(client) => client.length(text),
+            Destination::Mastodon(client) => client.length(text),
+            Destination::Bluesky(client) => client.length(text),
+            Destination::DryRun(client) => client.length(text),
+        }
+    }
+}
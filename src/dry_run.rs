@@ -0,0 +1,239 @@
+use crate::bluesky::MAX_POST_LENGTH;
+use crate::mastodon::MAX_STATUS_LENGTH;
+use crate::storage::append_line;
+use crate::twitter::text::{char_length, weighted_length, MAX_TWEET_LENGTH};
+use crate::twitter::types::{Postable, TwitterBuilder, TwitterClient};
+use crate::types::{DestinationConfig, DryRunConfig, Target};
+use async_trait::async_trait;
+use mime_guess::Mime;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::sync::Mutex;
+
+/// Where a dry run writes the posts, shared by the clients of all the destinations
+#[derive(Clone)]
+pub struct DryRunOutput {
+    /// Stdout when `None`
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl DryRunOutput {
+    pub async fn open(config: &DryRunConfig) -> Self {
+        let file = match &config.file {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .await
+                    .unwrap_or_else(|e| panic!("Error opening dry_run.file {}: {}", path, e));
+                log::info!("Dry run, the posts are written to {}", path);
+                Some(Arc::new(Mutex::new(file)))
+            }
+            None => None,
+        };
+        DryRunOutput { file }
+    }
+
+    async fn write(&self, entry: &Value) {
+        match &self.file {
+            Some(file) => append_line(&mut *file.lock().await, entry).await,
+            None => println!("{}", entry),
+        }
+    }
+}
+
+/// Writes each post that would be sent to a destination, as a JSON line, instead of sending it
+///
+/// The ids it hands out are fake, so the posts of a thread reply to each other, and the media
+/// attached are described by their file, type and size.
+#[derive(Clone)]
+pub struct DryRunClient {
+    builder: TwitterBuilder,
+    output: DryRunOutput,
+    route: String,
+    destination: String,
+    max_length: usize,
+    length: fn(&str) -> usize,
+    /// Shared by the clones, the uploader and the poster hold different ones
+    media: Arc<std::sync::Mutex<HashMap<u64, Value>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl DryRunClient {
+    /// Stands for the destination of the route, splitting the threads as it would
+    pub fn new(output: DryRunOutput, route: &str, target: &Target) -> Self {
+        let (max_length, length): (usize, fn(&str) -> usize) = match target.config {
+            DestinationConfig::Twitter(_) => (MAX_TWEET_LENGTH, weighted_length),
+            DestinationConfig::Mastodon(_) => (MAX_STATUS_LENGTH, char_length),
+            DestinationConfig::Bluesky(_) => (MAX_POST_LENGTH, weighted_length),
+        };
+        DryRunClient {
+            builder: TwitterBuilder::new(),
+            output,
+            route: route.to_string(),
+            destination: target.name.clone(),
+            max_length,
+            length,
+            media: Default::default(),
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl Postable for DryRunClient {
+    async fn upload_media(
+        &mut self,
+        file: &Path,
+        media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let size = tokio::fs::metadata(file).await?.len();
+        let id = self.next_id();
+        self.media.lock().unwrap().insert(
+            id,
+            json!({
+                "file": file.to_string_lossy(),
+                "mime": media_type.to_string(),
+                "size": size,
+            }),
+        );
+        Ok(id)
+    }
+
+    async fn send(&mut self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let tweet_id = format!("dry-run-{}", self.next_id());
+        let media: Vec<Value> = {
+            let media = self.media.lock().unwrap();
+            self.builder
+                .media_ids()
+                .iter()
+                .map(|id| media.get(id).cloned().unwrap_or(Value::Null))
+                .collect()
+        };
+        self.output
+            .write(&json!({
+                "route": self.route,
+                "destination": self.destination,
+                "id": tweet_id,
+                "text": self.builder.text(),
+                "reply_to": self.builder.reply_to(),
+                "quote": self.builder.quote(),
+                "media": media,
+            }))
+            .await;
+        Ok(tweet_id)
+    }
+
+    async fn delete(&mut self, tweet_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.output
+            .write(&json!({
+                "route": self.route,
+                "destination": self.destination,
+                "delete": tweet_id,
+            }))
+            .await;
+        Ok(())
+    }
+}
+
+impl TwitterClient for DryRunClient {
+    fn new_builder(&mut self) -> &mut TwitterBuilder {
+        self.builder = TwitterBuilder::new();
+        &mut self.builder
+    }
+
+    fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    fn length(&self, text: &str) -> usize {
+        (self.length)(text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::MastodonConfig;
+    use crate::util::TempDir;
+
+    #[tokio::test]
+    async fn test_writes_thread_with_media() {
+        let dir = TempDir::new("dry-run");
+        let image = dir.join("image.png");
+        tokio::fs::write(&image, b"image").await.unwrap();
+        let path = dir.join("dry_run.jsonl");
+
+        let output = DryRunOutput::open(&DryRunConfig {
+            file: Some(path.to_string_lossy().to_string()),
+            save_state: false,
+        })
+        .await;
+        let target = Target {
+            name: "mastodon".to_string(),
+            config: DestinationConfig::Mastodon(MastodonConfig {
+                instance: "https://example.social".to_string(),
+                access_token: "token".to_string(),
+            }),
+            data_dir: dir.path().to_string(),
+            key: "chat".to_string(),
+        };
+        let mut client = DryRunClient::new(output, "news", &target);
+        assert_eq!(client.max_length(), MAX_STATUS_LENGTH);
+        assert_eq!(client.length("日本語"), 3);
+
+        let media_id = client
+            .upload_media(&image, &"image/png".parse().unwrap())
+            .await
+            .unwrap();
+        let builder = client.new_builder();
+        builder.set_text("First".to_string());
+        builder.add_media(media_id);
+        let first = client.send().await.unwrap();
+        let builder = client.new_builder();
+        builder.set_text("Second".to_string());
+        builder.set_reply_to(Some(first.clone()));
+        let second = client.send().await.unwrap();
+        client.delete(&second).await.unwrap();
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                json!({
+                    "route": "news",
+                    "destination": "mastodon",
+                    "id": first,
+                    "text": "First",
+                    "reply_to": null,
+                    "quote": null,
+                    "media": [{"file": image.to_string_lossy(), "mime": "image/png", "size": 5}],
+                }),
+                json!({
+                    "route": "news",
+                    "destination": "mastodon",
+                    "id": second,
+                    "text": "Second",
+                    "reply_to": first,
+                    "quote": null,
+                    "media": [],
+                }),
+                json!({"route": "news", "destination": "mastodon", "delete": second}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use crate::types::{ForwardPolicy, Post, Route};
+use regex::Regex;
+use serde::Deserialize;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// What to do with the posts matching a rule
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    #[default]
+    Include,
+    Exclude,
+}
+
+/// A rule as declared in a `[[filters]]` table of `config.toml`, a post matches it when it meets
+/// every condition given
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FilterRule {
+    /// Shown in the logs, defaults to the position of the rule
+    pub(crate) name: Option<String>,
+    pub(crate) action: FilterAction,
+    /// The text contains the keyword, ignoring case
+    pub(crate) keyword: Option<String>,
+    /// The text matches the regex
+    pub(crate) regex: Option<String>,
+    /// The text has the hashtag, ignoring case
+    pub(crate) hashtag: Option<String>,
+    /// Any media of the post has one of these types, e.g. `"image"` or `"video/mp4"`, `"text"`
+    /// matches the posts without media
+    pub(crate) media: Option<Vec<String>>,
+    /// Number of characters of the text
+    pub(crate) min_length: Option<usize>,
+    pub(crate) max_length: Option<usize>,
+    /// The signature of the author of a channel post
+    pub(crate) author: Option<String>,
+    pub(crate) forwarded: Option<bool>,
+    /// The post was published in this time of the day, in UTC, e.g. `"22:00-06:00"`
+    pub(crate) time: Option<String>,
+}
+
+struct Rule {
+    name: String,
+    action: FilterAction,
+    keyword: Option<String>,
+    regex: Option<Regex>,
+    hashtag: Option<Regex>,
+    media: Option<Vec<String>>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    author: Option<String>,
+    forwarded: Option<bool>,
+    /// Start and end of the window, in seconds since midnight
+    time: Option<(i64, i64)>,
+}
+
+impl Rule {
+    fn new(index: usize, config: &FilterRule) -> Self {
+        Rule {
+            name: config
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("#{}", index + 1)),
+            action: config.action,
+            keyword: config.keyword.as_ref().map(|k| k.to_lowercase()),
+            regex: config.regex.as_deref().map(compile),
+            hashtag: config.hashtag.as_ref().map(|hashtag| {
+                let tag = regex::escape(hashtag.trim_start_matches('#'));
+                compile(&format!(r"(?i)#{}\b", tag))
+            }),
+            media: config.media.clone(),
+            min_length: config.min_length,
+            max_length: config.max_length,
+            author: config.author.clone(),
+            forwarded: config.forwarded,
+            time: config.time.as_deref().map(parse_window),
+        }
+    }
+
+    fn matches(&self, post: &Post) -> bool {
+        let text = post.text();
+        let length = text.chars().count();
+        let keyword = |k: &String| text.to_lowercase().contains(k);
+        let media = |types: &Vec<String>| {
+            if post.attachments().is_empty() {
+                return types.iter().any(|t| t == "text");
+            }
+            post.attachments().iter().any(|a| {
+                let mime = a.mime();
+                types
+                    .iter()
+                    .any(|t| t == mime.type_().as_str() || t == mime.essence_str())
+            })
+        };
+        let time = |(start, end): &(i64, i64)| {
+            let second = post.date().rem_euclid(SECONDS_PER_DAY);
+            if start <= end {
+                *start <= second && second < *end
+            } else {
+                *start <= second || second < *end
+            }
+        };
+
+        self.keyword.as_ref().map(keyword).unwrap_or(true)
+            && self
+                .regex
+                .as_ref()
+                .map(|r| r.is_match(text))
+                .unwrap_or(true)
+            && self
+                .hashtag
+                .as_ref()
+                .map(|r| r.is_match(text))
+                .unwrap_or(true)
+            && self.media.as_ref().map(media).unwrap_or(true)
+            && self.min_length.map(|min| length >= min).unwrap_or(true)
+            && self.max_length.map(|max| length <= max).unwrap_or(true)
+            && self
+                .author
+                .as_ref()
+                .map(|a| post.author() == Some(a.as_str()))
+                .unwrap_or(true)
+            && self
+                .forwarded
+                .map(|f| post.is_forwarded() == f)
+                .unwrap_or(true)
+            && self.time.as_ref().map(time).unwrap_or(true)
+    }
+}
+
+fn compile(pattern: &str) -> Regex {
+    Regex::new(pattern).unwrap_or_else(|e| panic!("Invalid filter pattern {}: {}", pattern, e))
+}
+
+/// Parses a `"HH:MM-HH:MM"` window into seconds since midnight
+fn parse_window(window: &str) -> (i64, i64) {
+    let time = |time: &str| -> Option<i64> {
+        let (hours, minutes) = time.trim().split_once(':')?;
+        let (hours, minutes): (i64, i64) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (hours <= 24 && minutes < 60).then_some((hours * 60 + minutes) * 60)
+    };
+    window
+        .split_once('-')
+        .and_then(|(start, end)| Some((time(start)?, time(end)?)))
+        .unwrap_or_else(|| {
+            panic!(
+                "Invalid filter time window {}, expected HH:MM-HH:MM",
+                window
+            )
+        })
+}
+
+/// Decides which posts of a route are mirrored
+///
+/// Empty posts and the ones with the `ignore` keyword are always skipped. Then the rules are
+/// checked in order and the first one matching decides. A post no rule matches is mirrored,
+/// unless there are include rules: then only the posts they match are.
+#[derive(Clone)]
+pub struct Filter {
+    ignore: String,
+    rules: std::sync::Arc<Vec<Rule>>,
+}
+
+impl Filter {
+    pub fn new(ignore: &str, rules: &[FilterRule]) -> Self {
+        Filter {
+            ignore: ignore.to_string(),
+            rules: std::sync::Arc::new(
+                rules
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rule)| Rule::new(i, rule))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The filter of a route, forwarded posts are excluded first if its policy is to skip them
+    pub fn of_route(route: &Route) -> Self {
+        let mut rules = route.filters.clone();
+        if route.forwards == ForwardPolicy::Skip {
+            rules.insert(
+                0,
+                FilterRule {
+                    name: Some("forwards".to_string()),
+                    action: FilterAction::Exclude,
+                    forwarded: Some(true),
+                    ..Default::default()
+                },
+            );
+        }
+        Filter::new(&route.ignore, &rules)
+    }
+
+    /// Whether the post is mirrored, the reason is logged when it is not
+    pub fn accepts(&self, post: &Post) -> bool {
+        if post.text().is_empty() && post.attachments().is_empty() {
+            return false;
+        }
+        if !self.ignore.is_empty() && post.text().contains(&self.ignore) {
+            log::info!("Skipping telegram post {}: has {}", post.id(), self.ignore);
+            return false;
+        }
+        match self.rules.iter().find(|rule| rule.matches(post)) {
+            Some(rule) if rule.action == FilterAction::Exclude => {
+                log::info!(
+                    "Skipping telegram post {}: excluded by rule {}",
+                    post.id(),
+                    rule.name
+                );
+                false
+            }
+            Some(_) => true,
+            None if self.rules.iter().any(|r| r.action == FilterAction::Include) => {
+                log::info!(
+                    "Skipping telegram post {}: no include rule matches",
+                    post.id()
+                );
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filter(config: &str) -> Filter {
+        #[derive(Deserialize)]
+        struct Filters {
+            filters: Vec<FilterRule>,
+        }
+        let filters: Filters = toml::from_str(config).unwrap();
+        Filter::new("#tgonly", &filters.filters)
+    }
+
+    fn post(text: &str) -> Post {
+        Post::new(1, text.to_string())
+    }
+
+    #[test]
+    fn test_exclude_rules() {
+        let filter = filter(
+            r#"
+            [[filters]]
+            name = "ads"
+            action = "exclude"
+            regex = "(?i)sponsored|promo"
+            [[filters]]
+            action = "exclude"
+            max_length = 3
+            "#,
+        );
+        assert!(filter.accepts(&post("Regular news")));
+        assert!(!filter.accepts(&post("Sponsored content")));
+        assert!(!filter.accepts(&post("ok")));
+        assert!(!filter.accepts(&post("News #tgonly")));
+        assert!(!filter.accepts(&post("")));
+    }
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let filter = filter(
+            r#"
+            [[filters]]
+            action = "include"
+            hashtag = "keep"
+            [[filters]]
+            action = "exclude"
+            keyword = "PROMO"
+            [[filters]]
+            action = "include"
+            forwarded = false
+            media = ["text"]
+            "#,
+        );
+        assert!(filter.accepts(&post("Promo #Keep")));
+        assert!(!filter.accepts(&post("#keeper promo")));
+        assert!(filter.accepts(&post("Regular news")));
+
+        let mut forwarded = Post::new(2, "Forwarded news".to_string());
+        forwarded.set_forward(Some(Default::default()));
+        assert!(!filter.accepts(&forwarded));
+    }
+
+    #[test]
+    fn test_time_window() {
+        assert_eq!(parse_window("08:30-17:00"), (30600, 61200));
+
+        let filter = filter(
+            r#"
+            [[filters]]
+            action = "exclude"
+            time = "22:00-06:00"
+            "#,
+        );
+        let mut night = post("At night");
+        night.set_date(SECONDS_PER_DAY * 100 + 23 * 3600);
+        let mut day = post("At noon");
+        day.set_date(SECONDS_PER_DAY * 100 + 12 * 3600);
+        assert!(!filter.accepts(&night));
+        assert!(filter.accepts(&day));
+    }
+}
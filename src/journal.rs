@@ -0,0 +1,235 @@
+use crate::storage::Storage;
+use crate::types::Post;
+use mime_guess::Mime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+pub(crate) const JOURNAL_FILE: &str = "journal";
+
+/// The steps a post goes through, in order
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Fetched,
+    Downloaded,
+    Uploaded,
+    /// Part of the thread was sent
+    Posting,
+    Posted,
+    /// The state and the mapping were saved, nothing is left to do
+    Done,
+}
+
+/// The last step completed for a post, with what is needed to resume from there
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    pub tg_id: i32,
+    pub step: Step,
+    /// The Twitter media ids uploaded, with their type
+    #[serde(default)]
+    pub media: Vec<(u64, String)>,
+    #[serde(default)]
+    pub tweet_ids: Vec<String>,
+    pub timestamp: u64,
+}
+
+impl JournalEntry {
+    /// The media uploaded, skipping any type that can't be parsed back
+    pub fn media(&self) -> Vec<(u64, Mime)> {
+        self.media
+            .iter()
+            .filter_map(|(id, mime)| Some((*id, mime.parse().ok()?)))
+            .collect()
+    }
+}
+
+/// Write-ahead journal of the posts going through the pipeline, kept in the `journal` file in
+/// the data dir
+///
+/// Each stage records the step it completed before handing the post over, so a post interrupted
+/// by a crash is resumed by the next run from its last completed step: nothing is tweeted twice
+/// and nothing is skipped. Edits are not journaled. Finished posts are dropped from the file
+/// when it is opened. Like the other stores, it is only kept in memory in a dry run. Clones
+/// share the same state.
+#[derive(Clone)]
+pub struct Journal {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    /// `None` in a dry run
+    file: Option<File>,
+    entries: BTreeMap<i32, JournalEntry>,
+}
+
+impl Journal {
+    pub async fn open(storage: &Storage, data_dir: &str) -> Journal {
+        let mut path = PathBuf::from(data_dir);
+        path.push(JOURNAL_FILE);
+
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => panic!("Error reading journal {}", e),
+        };
+        let mut entries = BTreeMap::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => {
+                    entries.insert(entry.tg_id, entry);
+                }
+                Err(e) => log::warn!("Ignoring invalid journal entry {:?}: {}", line, e),
+            }
+        }
+        entries.retain(|_, entry| entry.step != Step::Done);
+        if storage.is_dry_run() {
+            return Journal {
+                inner: Arc::new(Mutex::new(Inner {
+                    file: None,
+                    entries,
+                })),
+            };
+        }
+
+        // Compacts the journal to the posts still in progress
+        let mut compacted = path.clone();
+        compacted.set_extension("tmp");
+        let lines: String = entries
+            .values()
+            .map(|entry| serde_json::to_string(entry).expect("Serialize journal") + "\n")
+            .collect();
+        fs::write(&compacted, lines).await.expect("Write journal");
+        fs::rename(&compacted, &path)
+            .await
+            .expect("Replace journal");
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path.as_path())
+            .await
+            .expect("Error opening journal");
+
+        Journal {
+            inner: Arc::new(Mutex::new(Inner {
+                file: Some(file),
+                entries,
+            })),
+        }
+    }
+
+    /// The progress of a post still in progress, if it is being resumed
+    pub async fn resume(&self, post: &Post) -> Option<JournalEntry> {
+        if post.is_edit() {
+            return None;
+        }
+        let inner = self.inner.lock().await;
+        inner
+            .entries
+            .get(&post.id())
+            .filter(|entry| entry.step != Step::Done)
+            .cloned()
+    }
+
+    /// Records that a post completed a step, the media and tweets already in the journal are
+    /// kept unless `update` replaces them
+    pub async fn record<F: FnOnce(&mut JournalEntry)>(&self, post: &Post, step: Step, update: F) {
+        if post.is_edit() {
+            return;
+        }
+        let mut inner = self.inner.lock().await;
+        let mut entry = match inner.entries.get(&post.id()) {
+            Some(entry) if entry.step != Step::Done && step != Step::Fetched => entry.clone(),
+            _ => JournalEntry {
+                tg_id: post.id(),
+                step,
+                media: vec![],
+                tweet_ids: vec![],
+                timestamp: 0,
+            },
+        };
+        entry.step = step;
+        entry.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time")
+            .as_secs();
+        update(&mut entry);
+
+        if let Some(file) = &mut inner.file {
+            let mut line = serde_json::to_string(&entry).expect("Serialize journal");
+            line.push('\n');
+            file.write_all(line.as_bytes()).await.expect("Save journal");
+            file.sync_data().await.expect("Sync journal");
+        }
+        inner.entries.insert(entry.tg_id, entry);
+    }
+
+    /// The ids of the posts that were interrupted, oldest first
+    pub async fn pending(&self) -> Vec<i32> {
+        self.inner
+            .lock()
+            .await
+            .entries
+            .values()
+            .filter(|entry| entry.step != Step::Done)
+            .map(|entry| entry.tg_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[tokio::test]
+    async fn test_resume_from_last_step() {
+        let dir = TempDir::new("journal");
+
+        let first = Post::new(1, "first".to_string());
+        let second = Post::new(2, "second".to_string());
+        let journal = Journal::open(&Storage::Files, dir.path()).await;
+        journal.record(&first, Step::Fetched, |_| {}).await;
+        journal
+            .record(&first, Step::Uploaded, |e| {
+                e.media = vec![(10, "image/jpeg".to_string())]
+            })
+            .await;
+        journal
+            .record(&first, Step::Posting, |e| {
+                e.tweet_ids = vec!["100".to_string()]
+            })
+            .await;
+        journal.record(&second, Step::Fetched, |_| {}).await;
+        journal.record(&second, Step::Done, |_| {}).await;
+
+        let journal = Journal::open(&Storage::Files, dir.path()).await;
+        assert_eq!(journal.pending().await, vec![1]);
+        let entry = journal.resume(&first).await.unwrap();
+        assert_eq!(entry.step, Step::Posting);
+        assert_eq!(entry.media(), vec![(10, mime_guess::mime::IMAGE_JPEG)]);
+        assert_eq!(entry.tweet_ids, vec!["100".to_string()]);
+        assert!(journal.resume(&second).await.is_none());
+
+        let mut edit = first.clone();
+        edit.set_edited(true);
+        assert!(journal.resume(&edit).await.is_none());
+
+        // A dry run resumes the posts of the journal but leaves it as it was
+        let dry_run = Storage::DryRun {
+            storage: Box::new(Storage::Files),
+            save_state: false,
+        };
+        let journal = Journal::open(&dry_run, dir.path()).await;
+        journal.record(&first, Step::Done, |_| {}).await;
+        assert!(journal.pending().await.is_empty());
+        let journal = Journal::open(&Storage::Files, dir.path()).await;
+        assert_eq!(journal.pending().await, vec![1]);
+    }
+}
@@ -2,17 +2,37 @@ extern crate core;
 
 use mime_guess::mime;
 use simple_logger::SimpleLogger;
+use std::env;
+use std::path::Path;
 use tokio::fs;
 
+use crate::dead_letter::{DeadLetterStatus, DeadLetters};
+use crate::destination::Destination;
+use crate::dry_run::{DryRunClient, DryRunOutput};
+use crate::mapping::MappingStore;
 use crate::mime::{APPLICATION_OCTET_STREAM, TEXT_VCARD};
 use crate::persistence::Persister;
+use crate::storage::Storage;
 use crate::telegram::telegram_client::GrammersClient;
-use crate::twitter::critter_client::CritterClient;
 use crate::twittergram::Twittergram;
-use crate::types::Cfg;
+use crate::types::{Cfg, DryRunConfig, Route, Target};
 
+mod bluesky;
+mod dead_letter;
+mod destination;
+mod dry_run;
+mod filter;
+mod journal;
+mod mapping;
+mod mastodon;
+mod migration;
 mod persistence;
+mod retry;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod storage;
 mod telegram;
+mod transform;
 mod twitter;
 mod twittergram;
 mod types;
@@ -33,14 +53,181 @@ async fn main() -> Result<()> {
         .await
         .expect("Could not find file config.toml");
 
-    let config: Cfg = toml::from_str(&config_file).unwrap();
+    let mut config: Cfg = toml::from_str(&config_file).unwrap();
 
     Persister::check_data_dir(&config.data_dir).await;
 
+    let args: Vec<String> = env::args().collect();
+    config.daemon |= args.iter().any(|a| a == "--daemon");
+    if args.get(1).map(|a| a == "--mapping").unwrap_or(false) {
+        let tg_id = args
+            .get(2)
+            .map(|id| id.parse().expect("Invalid Telegram message id"));
+        show_mapping(&config, tg_id).await;
+        return Ok(());
+    }
+    if args.get(1).map(|a| a == "--dead-letters").unwrap_or(false) {
+        show_dead_letters(&config).await;
+        return Ok(());
+    }
+    if args.get(1).map(|a| a == "--requeue").unwrap_or(false) {
+        let tg_id = args
+            .get(2)
+            .map(|id| id.parse().expect("Invalid Telegram message id"));
+        requeue(&config, tg_id).await;
+        return Ok(());
+    }
+    if args.get(1).map(|a| a == "--migrate").unwrap_or(false) {
+        let (Some(route), Some(destination)) = (args.get(2), args.get(3)) else {
+            panic!("Usage: twittergram --migrate <route> <destination>");
+        };
+        migrate(&config, route, destination).await;
+        return Ok(());
+    }
+
+    if let Some(output) = args.iter().find(|a| a.starts_with("--dry-run")) {
+        let file = match output.as_str() {
+            "--dry-run" => None,
+            "--dry-run=file" => Some(format!("{}/dry_run.jsonl", config.data_dir)),
+            _ => panic!(
+                "Unknown option {}, expected --dry-run or --dry-run=file",
+                output
+            ),
+        };
+        config.dry_run = Some(DryRunConfig {
+            file,
+            save_state: args.iter().any(|a| a == "--save-state"),
+        });
+    }
+    let dry_run = match &config.dry_run {
+        Some(dry_run) => Some(DryRunOutput::open(dry_run).await),
+        None => None,
+    };
+
+    let storage = Storage::open(&config);
+    let mut routes = vec![];
+    for route in config.routes() {
+        if migration::legacy_progress(&storage, &route).await {
+            let names: Vec<String> = route.targets().into_iter().map(|t| t.name).collect();
+            panic!(
+                "Route {} keeps the progress of a destination in {}, where a previous version \
+                 left it. Move it to the destination it belongs to with \
+                 `twittergram --migrate {} <destination>`, one of {}",
+                route.name,
+                route.data_dir,
+                route.name,
+                names.join(", ")
+            );
+        }
+        Persister::check_data_dir(&route.data_dir).await;
+        let mut destinations = vec![];
+        for target in route.targets() {
+            Persister::check_data_dir(&target.data_dir).await;
+            migration::import_files(&storage, &target).await;
+            let destination = match &dry_run {
+                Some(output) => {
+                    Destination::DryRun(DryRunClient::new(output.clone(), &route.name, &target))
+                }
+                None => Destination::open(&target).await,
+            };
+            destinations.push((target, destination));
+        }
+        routes.push((route, destinations));
+    }
+
     let telegram_client = GrammersClient::new(&config).await;
-    let twitter_client = CritterClient::new(&config);
 
-    Twittergram::new(config, telegram_client, twitter_client)
+    Twittergram::new(config, telegram_client, routes)
         .run()
         .await
 }
+
+/// Prints the tweets posted for a Telegram message, or for the latest messages mirrored, of
+/// every destination of every route
+async fn show_mapping(config: &Cfg, tg_id: Option<i32>) {
+    let storage = Storage::open(config);
+    for (route, target) in targets(config) {
+        let mappings = MappingStore::open(&storage, &target.data_dir, &target.key).await;
+        let entries = match tg_id {
+            Some(id) => mappings.get(id).await.into_iter().collect(),
+            None => mappings.latest(20).await,
+        };
+        println!("Route {} to {}:", route.name, target.name);
+        if entries.is_empty() {
+            println!("No mapping found");
+        }
+        for mapping in entries {
+            println!("{}", serde_json::to_string(&mapping).unwrap());
+        }
+    }
+}
+
+/// Prints the posts that could not be mirrored, and the ones waiting to be mirrored again, of
+/// every destination of every route
+async fn show_dead_letters(config: &Cfg) {
+    let storage = Storage::open(config);
+    for (route, target) in targets(config) {
+        let dead_letters = DeadLetters::open(&storage, &target.data_dir, &target.key).await;
+        let mut entries = dead_letters.with_status(DeadLetterStatus::Failed).await;
+        entries.extend(dead_letters.with_status(DeadLetterStatus::Requeued).await);
+        println!("Route {} to {}:", route.name, target.name);
+        if entries.is_empty() {
+            println!("No dead letter found");
+        }
+        for letter in entries {
+            println!("{}", serde_json::to_string(&letter).unwrap());
+        }
+    }
+}
+
+/// Flags the posts that could not be mirrored, or a single one, to be mirrored on the next run
+async fn requeue(config: &Cfg, tg_id: Option<i32>) {
+    let storage = Storage::open(config);
+    for (route, target) in targets(config) {
+        let dead_letters = DeadLetters::open(&storage, &target.data_dir, &target.key).await;
+        let count = dead_letters.requeue(tg_id).await;
+        println!(
+            "Route {} to {}: {} post(s) requeued",
+            route.name, target.name, count
+        );
+    }
+}
+
+/// Moves the progress a previous version kept in the data dir of a route to the folder of the
+/// destination it belongs to
+async fn migrate(config: &Cfg, route: &str, destination: &str) {
+    let storage = Storage::open(config);
+    let route = config
+        .routes()
+        .into_iter()
+        .find(|r| r.name == route)
+        .unwrap_or_else(|| panic!("Unknown route {}", route));
+    let target = route
+        .targets()
+        .into_iter()
+        .find(|t| t.name == destination)
+        .unwrap_or_else(|| panic!("Route {} has no {} destination", route.name, destination));
+    match migration::migrate(&storage, &route, &target).await {
+        Ok(count) => println!(
+            "Route {}: {} file(s) moved to {}",
+            route.name, count, target.data_dir
+        ),
+        Err(e) => println!("Route {}: nothing was moved, {}", route.name, e),
+    }
+}
+
+/// The destinations of every route, whose progress exists
+fn targets(config: &Cfg) -> Vec<(Route, Target)> {
+    config
+        .routes()
+        .into_iter()
+        .flat_map(|route| {
+            route
+                .targets()
+                .into_iter()
+                .filter(|target| Path::new(&target.data_dir).is_dir())
+                .map(move |target| (route.clone(), target))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
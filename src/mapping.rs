@@ -0,0 +1,191 @@
+#[cfg(feature = "sqlite")]
+use crate::sqlite::Database;
+use crate::storage::{append_line, open_lines, Storage};
+use crate::types::Post;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::sync::Mutex;
+
+pub(crate) const MAPPING_FILE: &str = "mapping";
+
+/// What was posted on Twitter for a Telegram message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Mapping {
+    pub tg_id: i32,
+    pub chat: String,
+    pub tweet_ids: Vec<String>,
+    pub media_ids: Vec<u64>,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub edit_date: Option<i64>,
+    #[serde(default)]
+    pub content_hash: Option<u64>,
+}
+
+impl Mapping {
+    pub fn from_post(post: &Post, chat: &str) -> Self {
+        Mapping {
+            tg_id: post.id(),
+            chat: chat.to_string(),
+            tweet_ids: post.tweet_ids().clone(),
+            media_ids: post.tw_attachments(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System time")
+                .as_secs(),
+            deleted: false,
+            edit_date: post.edit_date(),
+            content_hash: Some(post.content_hash()),
+        }
+    }
+}
+
+/// Durable store of the Telegram message id to tweet ids mapping
+///
+/// Entries are appended as JSON lines to the `mapping` file in the data dir, a later entry for
+/// the same Telegram id replaces the previous one, or kept in the `mapping` table with the SQLite
+/// storage. The store is cheap to clone and all clones share the same state, so it can be queried
+/// by a stage while another one records.
+#[derive(Clone)]
+pub struct MappingStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    backend: Backend,
+    entries: BTreeMap<i32, Mapping>,
+}
+
+enum Backend {
+    File(File),
+    /// Not saved, for a dry run
+    Memory,
+    #[cfg(feature = "sqlite")]
+    Sqlite(Database),
+}
+
+impl MappingStore {
+    /// Loads the mapping of the chat, `chat` only selects the rows of the SQLite storage
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    pub async fn open(storage: &Storage, data_dir: &str, chat: &str) -> MappingStore {
+        let (backend, mappings) = match storage.source() {
+            Storage::Files => {
+                let (file, mappings) = open_lines::<Mapping>(data_dir, MAPPING_FILE).await;
+                (Backend::File(file), mappings)
+            }
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(database) => {
+                (Backend::Sqlite(database.clone()), database.mappings(chat))
+            }
+            Storage::DryRun { .. } => unreachable!("A dry run wraps the storage it reads from"),
+        };
+        let backend = if storage.is_dry_run() {
+            Backend::Memory
+        } else {
+            backend
+        };
+        let entries = mappings
+            .into_iter()
+            .map(|mapping| (mapping.tg_id, mapping))
+            .collect();
+
+        MappingStore {
+            inner: Arc::new(Mutex::new(Inner { backend, entries })),
+        }
+    }
+
+    /// Stores the mapping, replacing any previous one for the same Telegram message
+    pub async fn record(&self, mapping: Mapping) {
+        let mut inner = self.inner.lock().await;
+        match &mut inner.backend {
+            Backend::File(file) => append_line(file, &mapping).await,
+            Backend::Memory => {}
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(database) => database.save_mapping(&mapping),
+        }
+        inner.entries.insert(mapping.tg_id, mapping);
+    }
+
+    /// Flags the tweets of a Telegram message as deleted, the entry is kept for reference
+    pub async fn mark_deleted(&self, tg_id: i32) {
+        if let Some(mut mapping) = self.get(tg_id).await {
+            mapping.deleted = true;
+            self.record(mapping).await;
+        }
+    }
+
+    /// The mapping of a Telegram message, if it was mirrored
+    pub async fn get(&self, tg_id: i32) -> Option<Mapping> {
+        self.inner.lock().await.entries.get(&tg_id).cloned()
+    }
+
+    /// The `count` most recent Telegram messages mirrored, newest first
+    pub async fn latest(&self, count: usize) -> Vec<Mapping> {
+        self.inner
+            .lock()
+            .await
+            .entries
+            .values()
+            .rev()
+            .take(count)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+    use std::path::PathBuf;
+    use tokio::fs;
+
+    fn mapping(tg_id: i32, tweet_ids: &[&str]) -> Mapping {
+        Mapping {
+            tg_id,
+            chat: "chat".to_string(),
+            tweet_ids: tweet_ids.iter().map(|id| id.to_string()).collect(),
+            media_ids: vec![],
+            timestamp: 100,
+            deleted: false,
+            edit_date: None,
+            content_hash: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_reload() {
+        let dir = TempDir::new("mapping");
+
+        let store = MappingStore::open(&Storage::Files, dir.path(), "chat").await;
+        assert_eq!(store.get(1).await, None);
+        store.record(mapping(1, &["10"])).await;
+        store.record(mapping(3, &["30", "31"])).await;
+        store.record(mapping(2, &["20"])).await;
+        store.record(mapping(1, &["11"])).await;
+        store.mark_deleted(2).await;
+        assert_eq!(store.get(1).await, Some(mapping(1, &["11"])));
+        let latest: Vec<i32> = store.latest(2).await.iter().map(|m| m.tg_id).collect();
+        assert_eq!(latest, vec![3, 2]);
+
+        // The last entry of a message wins, a partial last line left by a crash is skipped
+        let mut path = PathBuf::from(dir.path());
+        path.push(MAPPING_FILE);
+        let mut content = fs::read_to_string(&path).await.unwrap();
+        content.push_str(r#"{"tg_id":4,"chat":"chat","tweet_"#);
+        fs::write(&path, content).await.unwrap();
+        let store = MappingStore::open(&Storage::Files, dir.path(), "chat").await;
+        assert_eq!(store.get(1).await, Some(mapping(1, &["11"])));
+        assert!(store.get(2).await.unwrap().deleted);
+        assert_eq!(store.get(4).await, None);
+        assert_eq!(store.latest(10).await.len(), 3);
+        store.record(mapping(4, &["40"])).await;
+        let store = MappingStore::open(&Storage::Files, dir.path(), "chat").await;
+        assert_eq!(store.get(4).await, Some(mapping(4, &["40"])));
+    }
+}
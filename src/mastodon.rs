@@ -0,0 +1,280 @@
+use crate::twitter::text::char_length;
+use crate::twitter::types::{Postable, TwitterBuilder, TwitterClient};
+use crate::types::MastodonConfig;
+use async_trait::async_trait;
+use mime_guess::Mime;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+/// Maximum length of a status on a default Mastodon instance
+pub const MAX_STATUS_LENGTH: usize = 500;
+
+/// How often, and how many times, the processing of an uploaded media is checked
+const PROCESSING_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const PROCESSING_POLL_ATTEMPTS: u32 = 150;
+
+#[derive(Deserialize)]
+struct Attachment {
+    id: String,
+    /// Missing while the media is being processed
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    id: String,
+}
+
+/// Posts to a Mastodon account through the REST API of its instance
+#[derive(Clone)]
+pub struct MastodonClient {
+    builder: TwitterBuilder,
+    http: Client,
+    instance: String,
+    access_token: String,
+    poll_interval: Duration,
+}
+
+impl MastodonClient {
+    pub fn new(config: &MastodonConfig) -> Self {
+        MastodonClient {
+            builder: TwitterBuilder::new(),
+            http: Client::new(),
+            instance: config.instance.trim_end_matches('/').to_string(),
+            access_token: config.access_token.clone(),
+            poll_interval: PROCESSING_POLL_INTERVAL,
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}{}", self.instance, path))
+            .bearer_auth(&self.access_token)
+    }
+
+    /// Fails with the body of the response, which holds the reason, if the request failed
+    async fn check(response: Response) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        Err(format!("Mastodon request failed with {}: {}", status, body).into())
+    }
+
+    /// Waits until the instance is done processing the media
+    async fn wait_processing(&self, id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for _ in 0..PROCESSING_POLL_ATTEMPTS {
+            tokio::time::sleep(self.poll_interval).await;
+            let response = self
+                .request(reqwest::Method::GET, &format!("/api/v1/media/{}", id))
+                .send()
+                .await?;
+            if response.status() == StatusCode::PARTIAL_CONTENT {
+                continue;
+            }
+            let attachment: Attachment = Self::check(response).await?.json().await?;
+            if attachment.url.is_some() {
+                return Ok(());
+            }
+        }
+        Err(format!("Media {} is still being processed", id).into())
+    }
+}
+
+#[async_trait]
+impl Postable for MastodonClient {
+    /// Uploads the media, large ones are processed asynchronously by the instance, it can only
+    /// be attached once processed
+    async fn upload_media(
+        &mut self,
+        file: &Path,
+        media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let filename = file
+            .file_name()
+            .map(|o| o.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let content = tokio::fs::read(file).await?;
+        let part = Part::bytes(content)
+            .file_name(filename)
+            .mime_str(media_type.as_ref())?;
+        let response = self
+            .request(reqwest::Method::POST, "/api/v2/media")
+            .multipart(Form::new().part("file", part))
+            .send()
+            .await?;
+        let processing = response.status() == StatusCode::ACCEPTED;
+        let attachment: Attachment = Self::check(response).await?.json().await?;
+        if processing || attachment.url.is_none() {
+            self.wait_processing(&attachment.id).await?;
+        }
+        Ok(attachment.id.parse()?)
+    }
+
+    async fn send(&mut self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let length = char_length(&self.builder.text());
+        if length > MAX_STATUS_LENGTH {
+            return Err(format!(
+                "Status is too long: {} characters, the maximum is {}",
+                length, MAX_STATUS_LENGTH
+            )
+            .into());
+        }
+        let status = json!({
+            "status": self.builder.text(),
+            "media_ids": self.builder.media_ids().iter().map(u64::to_string).collect::<Vec<_>>(),
+            "in_reply_to_id": self.builder.reply_to(),
+        });
+        let mut request = self.request(reqwest::Method::POST, "/api/v1/statuses");
+        // The same part of a post sent again, e.g. when retrying after a timeout or resuming a
+        // thread with media uploaded again, is only posted once
+        if let Some(source) = self.builder.source() {
+            request = request.header("Idempotency-Key", format!("twittergram-{}", source));
+        }
+        let response = request.json(&status).send().await?;
+        let status: Status = Self::check(response).await?.json().await?;
+        Ok(status.id)
+    }
+
+    async fn delete(&mut self, tweet_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let response = self
+            .request(
+                reqwest::Method::DELETE,
+                &format!("/api/v1/statuses/{}", tweet_id),
+            )
+            .send()
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+}
+
+impl TwitterClient for MastodonClient {
+    fn new_builder(&mut self) -> &mut TwitterBuilder {
+        self.builder = TwitterBuilder::new();
+        &mut self.builder
+    }
+
+    fn max_length(&self) -> usize {
+        MAX_STATUS_LENGTH
+    }
+
+    fn length(&self, text: &str) -> usize {
+        char_length(text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+    use wiremock::matchers::{body_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(server: &MockServer) -> MastodonClient {
+        let mut client = MastodonClient::new(&MastodonConfig {
+            instance: server.uri(),
+            access_token: "token".to_string(),
+        });
+        client.poll_interval = Duration::from_millis(10);
+        client
+    }
+
+    #[tokio::test]
+    async fn test_upload_waits_for_processing() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2/media"))
+            .and(header("Authorization", "Bearer token"))
+            .respond_with(
+                ResponseTemplate::new(202).set_body_json(json!({"id": "12", "url": null})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/media/12"))
+            .respond_with(ResponseTemplate::new(206).set_body_json(json!({"id": "12"})))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/media/12"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"id": "12", "url": "https://files/12.mp4"})),
+            )
+            .mount(&server)
+            .await;
+
+        let dir = TempDir::new("mastodon");
+        let file = dir.join("video.mp4");
+        tokio::fs::write(&file, b"video").await.unwrap();
+        let mut client = client(&server);
+        let id = client
+            .upload_media(&file, &"video/mp4".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(id, 12);
+    }
+
+    #[tokio::test]
+    async fn test_reply_with_media_and_delete() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .and(header("Idempotency-Key", "twittergram-42-0-1"))
+            .and(body_json(json!({
+                "status": "Hello",
+                "media_ids": ["12", "13"],
+                "in_reply_to_id": "100",
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "101"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/statuses/101"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "101"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = client(&server);
+        let builder = client.new_builder();
+        builder.set_text("Hello".to_string());
+        builder.add_media(12);
+        builder.add_media(13);
+        builder.set_reply_to(Some("100".to_string()));
+        builder.set_source(42, None, 1);
+        assert_eq!(client.send().await.unwrap(), "101");
+        client.delete("101").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .respond_with(
+                ResponseTemplate::new(422)
+                    .set_body_json(json!({"error": "Validation failed: Text can't be blank"})),
+            )
+            .mount(&server)
+            .await;
+
+        let mut client = client(&server);
+        client.new_builder().set_text(String::new());
+        let error = client.send().await.unwrap_err();
+        assert!(!crate::twitter::types::is_retryable(&*error));
+
+        client.new_builder().set_text("a".repeat(501));
+        assert!(client.send().await.is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::bluesky::BLOBS_FILE;
+#[cfg(feature = "sqlite")]
+use crate::dead_letter::DeadLetter;
+use crate::dead_letter::DEAD_LETTER_FILE;
+use crate::journal::JOURNAL_FILE;
+#[cfg(feature = "sqlite")]
+use crate::mapping::Mapping;
+use crate::mapping::MAPPING_FILE;
+use crate::persistence::{BACKUP_EXTENSION, STATE_FILE};
+#[cfg(feature = "sqlite")]
+use crate::storage::open_lines;
+use crate::storage::Storage;
+use crate::twitter::rate_limit::RATE_LIMIT_FILE;
+use crate::types::{Route, Target};
+use std::path::PathBuf;
+use tokio::fs;
+
+/// The files of the progress of a destination
+fn progress_files() -> Vec<String> {
+    vec![
+        STATE_FILE.to_string(),
+        format!("{}.{}", STATE_FILE, BACKUP_EXTENSION),
+        MAPPING_FILE.to_string(),
+        DEAD_LETTER_FILE.to_string(),
+        JOURNAL_FILE.to_string(),
+        RATE_LIMIT_FILE.to_string(),
+        BLOBS_FILE.to_string(),
+    ]
+}
+
+/// The files of the progress the SQLite storage keeps in the database instead
+#[cfg(feature = "sqlite")]
+fn imported_files() -> Vec<String> {
+    vec![
+        STATE_FILE.to_string(),
+        format!("{}.{}", STATE_FILE, BACKUP_EXTENSION),
+        MAPPING_FILE.to_string(),
+        DEAD_LETTER_FILE.to_string(),
+    ]
+}
+
+/// Added to the files once imported into the database
+#[cfg(feature = "sqlite")]
+const IMPORTED_EXTENSION: &str = "imported";
+
+fn path_of(dir: &str, name: &str) -> PathBuf {
+    let mut path = PathBuf::from(dir);
+    path.push(name);
+    path
+}
+
+/// Whether the route keeps the progress of a destination in its own data dir, as it did before
+/// each destination had its own folder. Which destination it belongs to can't be told, it is
+/// only moved by [`migrate`]
+pub async fn legacy_progress(storage: &Storage, route: &Route) -> bool {
+    for name in progress_files() {
+        if fs::try_exists(path_of(&route.data_dir, &name))
+            .await
+            .unwrap_or(false)
+        {
+            return true;
+        }
+    }
+    match storage.source() {
+        #[cfg(feature = "sqlite")]
+        Storage::Sqlite(database) => database.has_progress(&route.chat_name),
+        _ => false,
+    }
+}
+
+/// Moves the progress kept in the data dir of the route to the folder of the destination it
+/// belongs to. Returns the number of files moved, fails without moving anything if the
+/// destination already has progress of its own
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+pub async fn migrate(storage: &Storage, route: &Route, target: &Target) -> Result<usize, String> {
+    let mut moved = vec![];
+    for name in progress_files() {
+        let from = path_of(&route.data_dir, &name);
+        if !fs::try_exists(&from).await.unwrap_or(false) {
+            continue;
+        }
+        let to = path_of(&target.data_dir, &name);
+        if fs::try_exists(&to).await.unwrap_or(false) {
+            return Err(format!("{} already exists", to.display()));
+        }
+        moved.push((from, to));
+    }
+    #[cfg(feature = "sqlite")]
+    if let Storage::Sqlite(database) = storage.source() {
+        if database.has_progress(&target.key) {
+            return Err(format!(
+                "{} already has progress in the database",
+                target.key
+            ));
+        }
+        database
+            .rekey(&route.chat_name, &target.key)
+            .map_err(|e| e.to_string())?;
+    }
+
+    fs::create_dir_all(&target.data_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    for (from, to) in &moved {
+        fs::rename(from, to).await.map_err(|e| e.to_string())?;
+    }
+    Ok(moved.len())
+}
+
+/// Imports the progress a destination kept in files into the database, the first time it is
+/// mirrored with the SQLite storage, so switching storage doesn't mirror the history again.
+/// The files are then renamed with the `imported` extension. Returns the number of files
+/// imported, refuses to start if the database already has progress of its own or in a dry run,
+/// which can't save the import
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+pub async fn import_files(storage: &Storage, target: &Target) -> usize {
+    #[cfg(feature = "sqlite")]
+    if let Storage::Sqlite(database) = storage.source() {
+        let mut files = vec![];
+        for name in imported_files() {
+            if fs::try_exists(path_of(&target.data_dir, &name))
+                .await
+                .unwrap_or(false)
+            {
+                files.push(name);
+            }
+        }
+        if files.is_empty() {
+            return 0;
+        }
+        if database.has_progress(&target.key) {
+            panic!(
+                "{} has progress both in the database and in {:?} of {}, remove the files to \
+                 keep the database",
+                target.key, files, target.data_dir
+            );
+        }
+        if storage.is_dry_run() {
+            panic!(
+                "The progress of {} in {} is imported into the database by the first run, \
+                 run once without --dry-run",
+                target.key, target.data_dir
+            );
+        }
+
+        let last_id = crate::persistence::saved_last_id(&target.data_dir, &target.key).await;
+        let mut mappings: Vec<Mapping> = vec![];
+        if files.iter().any(|name| name == MAPPING_FILE) {
+            mappings = open_lines(&target.data_dir, MAPPING_FILE).await.1;
+        }
+        let mut letters: Vec<DeadLetter> = vec![];
+        if files.iter().any(|name| name == DEAD_LETTER_FILE) {
+            letters = open_lines(&target.data_dir, DEAD_LETTER_FILE).await.1;
+        }
+        for mapping in &mut mappings {
+            mapping.chat = target.key.clone();
+        }
+        for letter in &mut letters {
+            letter.chat = target.key.clone();
+        }
+        database
+            .import(&target.key, last_id, &mappings, &letters)
+            .unwrap_or_else(|e| panic!("Error importing {}: {}", target.data_dir, e));
+        for name in &files {
+            let from = path_of(&target.data_dir, name);
+            let to = path_of(
+                &target.data_dir,
+                &format!("{}.{}", name, IMPORTED_EXTENSION),
+            );
+            fs::rename(&from, &to)
+                .await
+                .unwrap_or_else(|e| panic!("Error renaming {:?}: {}", from, e));
+        }
+        log::info!(
+            "Imported the progress of {} from {} into the database: last id {:?}, {} mappings, \
+             {} dead letters",
+            target.key,
+            target.data_dir,
+            last_id,
+            mappings.len(),
+            letters.len()
+        );
+        return files.len();
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::DestinationConfig;
+    use crate::util::TempDir;
+
+    #[tokio::test]
+    async fn test_migrate_to_destination() {
+        let dir = TempDir::new("migration");
+        let config = r#"
+            name = "news"
+            chat_name = "news"
+            [mastodon]
+            instance = "https://example.social"
+            access_token = "token"
+        "#;
+        let route = Route {
+            data_dir: dir.path().to_string(),
+            ..toml::from_str(config).unwrap()
+        };
+        let storage = Storage::Files;
+        assert!(!legacy_progress(&storage, &route).await);
+
+        fs::write(path_of(&route.data_dir, STATE_FILE), r#"{"tg_id": 42}"#)
+            .await
+            .unwrap();
+        fs::write(path_of(&route.data_dir, MAPPING_FILE), "")
+            .await
+            .unwrap();
+        assert!(legacy_progress(&storage, &route).await);
+
+        let target = route.targets().remove(0);
+        assert!(matches!(target.config, DestinationConfig::Mastodon(_)));
+        assert_eq!(migrate(&storage, &route, &target).await, Ok(2));
+        assert!(!legacy_progress(&storage, &route).await);
+        let state = fs::read_to_string(path_of(&target.data_dir, STATE_FILE))
+            .await
+            .unwrap();
+        assert_eq!(state, r#"{"tg_id": 42}"#);
+
+        // The progress of a destination is never overwritten
+        fs::write(path_of(&route.data_dir, STATE_FILE), "{}")
+            .await
+            .unwrap();
+        assert!(migrate(&storage, &route, &target).await.is_err());
+        assert!(legacy_progress(&storage, &route).await);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_import_files_into_database() {
+        use crate::sqlite::Database;
+
+        let dir = TempDir::new("import");
+        let data_dir = dir.path();
+        let storage = Storage::Sqlite(Database::open(data_dir));
+        // Two routes mirroring the same chat keep their progress apart
+        let route = |name: &str| -> Route {
+            let config = format!(
+                "name = \"{}\"\nchat_name = \"news\"\n[mastodon]\ninstance = \"https://example.social\"\naccess_token = \"token\"",
+                name
+            );
+            Route {
+                data_dir: format!("{}/{}", data_dir, name),
+                ..toml::from_str(&config).unwrap()
+            }
+        };
+        let news = route("news").targets().remove(0);
+        let digest = route("digest").targets().remove(0);
+        assert_ne!(news.key, digest.key);
+
+        fs::create_dir_all(&news.data_dir).await.unwrap();
+        fs::write(path_of(&news.data_dir, STATE_FILE), r#"{"tg_id": 42}"#)
+            .await
+            .unwrap();
+        let mapping = r#"{"tg_id":5,"chat":"news","tweet_ids":["1"],"media_ids":[],"timestamp":1}"#;
+        fs::write(
+            path_of(&news.data_dir, MAPPING_FILE),
+            format!("{}\n{}\n", mapping, mapping.replace(r#"["1"]"#, r#"["2"]"#)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(import_files(&storage, &news).await, 2);
+        assert_eq!(import_files(&storage, &news).await, 0);
+        assert_eq!(import_files(&storage, &digest).await, 0);
+        let Storage::Sqlite(database) = &storage else {
+            unreachable!()
+        };
+        assert_eq!(database.last_id(&news.key), Some(42));
+        assert_eq!(database.last_id(&digest.key), None);
+        let mappings = database.mappings(&news.key);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].tweet_ids, vec!["2".to_string()]);
+        assert_eq!(mappings[0].chat, news.key);
+        assert!(fs::try_exists(path_of(&news.data_dir, "state.imported"))
+            .await
+            .unwrap());
+    }
+}
@@ -1,53 +1,193 @@
+use crate::dead_letter::DeadLetters;
+use crate::journal::{Journal, Step};
+use crate::mapping::{Mapping, MappingStore};
+use crate::retry::RetryPolicy;
+use crate::storage::Storage;
 use crate::types::Post;
 use crate::types::{Runnable, Sink};
 use serde::{Deserialize, Serialize};
-use std::io::{ErrorKind, SeekFrom};
-use std::path::PathBuf;
+use serde_json::Value;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
 
+/// Saves the progress of a route: the last Telegram message processed and what was posted for
+/// each message, in the files of the data dir or in the SQLite database, along with the history
+/// of the runs
 pub struct Persister {
-    state_file: File,
+    storage: Storage,
+    state_path: PathBuf,
     state: State,
+    chat: String,
+    mappings: MappingStore,
+    retry: RetryPolicy,
+    dead_letters: DeadLetters,
+    journal: Journal,
+    /// The id of this run in the history
+    #[cfg(feature = "sqlite")]
+    run: Option<i64>,
     receiver: Option<Receiver<Post>>,
 }
 
-const STATE_FILE: &str = "state";
+pub(crate) const STATE_FILE: &str = "state";
 
-#[derive(Serialize, Deserialize)]
+/// The previous state, kept on every write
+pub(crate) const BACKUP_EXTENSION: &str = "bak";
+
+/// The state being written, renamed to the state file once complete
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Version of the state schema, the state without version is version 1: `{"tg_id": N}`
+const STATE_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct State {
+    version: u32,
     tg_id: i32,
+    chat: String,
+    /// Unix timestamp of the last write
+    updated: u64,
+}
+
+impl State {
+    fn new(chat: &str) -> Self {
+        State {
+            version: STATE_VERSION,
+            tg_id: -1,
+            chat: chat.to_string(),
+            updated: 0,
+        }
+    }
+
+    /// Parses a state of any known version, migrating it to the current one
+    fn parse(content: &str, chat: &str) -> Result<State, String> {
+        let value: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        let version = match value.get("version") {
+            None => 1,
+            Some(version) => version.as_u64().ok_or("Invalid state version")?,
+        };
+        match version {
+            1 => {
+                let tg_id = value
+                    .get("tg_id")
+                    .and_then(Value::as_i64)
+                    .ok_or("Missing tg_id")?;
+                log::info!("Migrating state of {} to version {}", chat, STATE_VERSION);
+                Ok(State {
+                    tg_id: tg_id as i32,
+                    ..State::new(chat)
+                })
+            }
+            2 => serde_json::from_value(value).map_err(|e| e.to_string()),
+            _ => Err(format!("Unsupported state version {}", version)),
+        }
+    }
+
+    /// Reads the state from the state file or, if it is corrupted, from its backup. An empty
+    /// state file, as created by the first start of previous versions, is a fresh state
+    async fn load(path: &Path, chat: &str) -> State {
+        let mut backup = path.to_path_buf();
+        backup.set_extension(BACKUP_EXTENSION);
+        let mut invalid = false;
+        for file in [path, backup.as_path()] {
+            match fs::read_to_string(file).await {
+                Ok(content) if content.trim().is_empty() => {}
+                Ok(content) => match State::parse(&content, chat) {
+                    Ok(state) => return state,
+                    Err(e) => {
+                        log::error!("Invalid state in {:?}: {}", file, e);
+                        invalid = true;
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => panic!("Error reading state {:?}: {}", file, e),
+            }
+        }
+        if invalid {
+            // Starting over would mirror the whole history again
+            panic!("No valid state found in {:?}", path);
+        }
+        State::new(chat)
+    }
+
+    /// Replaces the state file at once: the new state is written and synced to a temporary file
+    /// that is then renamed over the state file, after the current one is copied to the backup
+    async fn write(&self, path: &Path) -> std::io::Result<()> {
+        let content = serde_json::to_string(self).expect("Serialize state");
+        let mut temp = path.to_path_buf();
+        temp.set_extension(TEMP_EXTENSION);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)
+            .await?;
+        file.write_all(content.as_bytes()).await?;
+        file.sync_all().await?;
+
+        if fs::metadata(path).await.is_ok() {
+            let mut backup = path.to_path_buf();
+            backup.set_extension(BACKUP_EXTENSION);
+            fs::copy(path, &backup).await?;
+        }
+        fs::rename(&temp, path).await?;
+        // The rename is only durable once the directory is synced
+        if let Some(dir) = path.parent() {
+            File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+/// The last id saved in the state file of the data dir, if there is one
+#[cfg(feature = "sqlite")]
+pub(crate) async fn saved_last_id(data_dir: &str, chat: &str) -> Option<i32> {
+    let mut path = PathBuf::from(data_dir);
+    path.push(STATE_FILE);
+    Some(State::load(&path, chat).await.tg_id).filter(|tg_id| *tg_id != -1)
 }
 
 impl Persister {
-    pub async fn new(data_file: &String) -> Persister {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        storage: &Storage,
+        data_file: &String,
+        chat: &str,
+        mappings: MappingStore,
+        retry: RetryPolicy,
+        dead_letters: DeadLetters,
+        journal: Journal,
+    ) -> Persister {
         Persister::check_data_dir(data_file).await;
 
         let mut path = PathBuf::from(data_file);
         path.push(STATE_FILE);
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(path.as_path())
-            .await
-            .expect("Error opening file");
-
-        let mut content: String = String::new();
-        let s = file.read_to_string(&mut content).await.expect("Read file");
-        let state: State = if s == 0 {
-            State { tg_id: -1 }
-        } else {
-            serde_json::from_str(&content).unwrap()
+        let state = match storage.source() {
+            Storage::Files => State::load(&path, chat).await,
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(database) => State {
+                tg_id: database.last_id(chat).unwrap_or(-1),
+                ..State::new(chat)
+            },
+            Storage::DryRun { .. } => unreachable!("A dry run wraps the storage it reads from"),
         };
         Persister {
-            state_file: file,
+            storage: storage.clone(),
+            state_path: path,
             state,
+            chat: chat.to_string(),
+            mappings,
+            retry,
+            dead_letters,
+            journal,
+            #[cfg(feature = "sqlite")]
+            run: None,
             receiver: None,
         }
     }
@@ -72,20 +212,82 @@ impl Persister {
             Err(e) => panic!("{}", e),
         }
     }
+    async fn record_mapping(&self, post: &Post) {
+        let mut mapping = Mapping::from_post(post, &self.chat);
+        if post.is_edit() && !post.is_repost() {
+            // Corrections are added to the tweets that are still up
+            if let Some(previous) = self.mappings.get(post.id()).await.filter(|m| !m.deleted) {
+                mapping.tweet_ids = [previous.tweet_ids, mapping.tweet_ids].concat();
+                mapping.media_ids = [previous.media_ids, mapping.media_ids].concat();
+            }
+        }
+        self.mappings.record(mapping).await;
+    }
+
+    /// Saves the state, retrying according to the retry policy. Gives up the whole run if the
+    /// state can't be saved, as mirroring more posts would repeat them on the next run
     async fn save_state(&mut self) {
-        let string = serde_json::to_string(&self.state).expect("Save to file");
-        self.state_file
-            .seek(SeekFrom::Start(0))
-            .await
-            .expect("Seek File");
-        self.state_file
-            .write_all((string).as_ref())
-            .await
-            .expect("Save file");
-        self.state_file
-            .set_len(string.len() as u64)
-            .await
-            .expect("truncate");
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.state.updated = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System time")
+                .as_secs();
+            match self.write_state().await {
+                Ok(_) => break,
+                Err(e)
+                    if self
+                        .retry
+                        .wait(attempt, "[Persister] Saving state", &e)
+                        .await =>
+                {
+                    continue
+                }
+                Err(e) => panic!("Error saving state {}", e),
+            }
+        }
+    }
+
+    async fn write_state(&self) -> std::io::Result<()> {
+        let storage = match &self.storage {
+            Storage::DryRun {
+                storage,
+                save_state: true,
+            } => storage,
+            Storage::DryRun { .. } => return Ok(()),
+            storage => storage,
+        };
+        match storage {
+            Storage::Files => self.state.write(&self.state_path).await,
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(database) => database
+                .save_last_id(&self.chat, self.state.tg_id)
+                .map_err(std::io::Error::other),
+            Storage::DryRun { .. } => unreachable!("A dry run wraps the storage it writes to"),
+        }
+    }
+
+    /// Starts a run in the history, only kept in the SQLite database
+    fn start_run(&mut self) {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(database) = &self.storage {
+            self.run = Some(database.start_run(&self.chat));
+        }
+    }
+
+    fn count_post(&self) {
+        #[cfg(feature = "sqlite")]
+        if let (Storage::Sqlite(database), Some(run)) = (&self.storage, self.run) {
+            database.count_post(run);
+        }
+    }
+
+    fn finish_run(&self) {
+        #[cfg(feature = "sqlite")]
+        if let (Storage::Sqlite(database), Some(run)) = (&self.storage, self.run) {
+            database.finish_run(run);
+        }
     }
 }
 
@@ -98,18 +300,69 @@ impl Sink<Post> for Persister {
 impl Runnable for Persister {
     fn run(mut self) -> JoinHandle<()> {
         tokio::spawn(async move {
+            self.start_run();
             loop {
                 match self.receiver.as_mut().unwrap().recv().await {
                     None => {
+                        self.finish_run();
                         break;
                     }
                     Some(post) => {
-                        self.state.tg_id = post.id();
-                        self.save_state().await;
-                        log::info!("Saved {:?}", self.state.tg_id);
+                        if !post.tweet_ids().is_empty() {
+                            self.record_mapping(&post).await;
+                            self.count_post();
+                        }
+                        self.dead_letters.resolve(post.id()).await;
+                        // Edits refer to posts that were already processed
+                        if post.id() > self.state.tg_id {
+                            self.state.tg_id = post.id();
+                            self.save_state().await;
+                            log::info!("Saved {:?}", self.state.tg_id);
+                        }
+                        self.journal.record(&post, Step::Done, |_| {}).await;
                     }
                 }
             }
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_parse_migrates_version_1() {
+        let state = State::parse(r#"{"tg_id": 42}"#, "chat").unwrap();
+        assert_eq!((state.version, state.tg_id), (STATE_VERSION, 42));
+        assert_eq!(state.chat, "chat");
+        assert!(State::parse(r#"{"version": 99, "tg_id": 42}"#, "chat").is_err());
+        assert!(State::parse(r#"{"tg_id": 4"#, "chat").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_keeps_backup() {
+        let dir = TempDir::new("state");
+        let path = dir.join(STATE_FILE);
+
+        let mut state = State::new("chat");
+        state.tg_id = 1;
+        state.write(&path).await.unwrap();
+        state.tg_id = 2;
+        state.write(&path).await.unwrap();
+        assert_eq!(State::load(&path, "chat").await.tg_id, 2);
+
+        // A corrupted state falls back to the backup
+        fs::write(&path, r#"{"version": 2, "tg"#).await.unwrap();
+        assert_eq!(State::load(&path, "chat").await.tg_id, 1);
+
+        // The empty state of a first start is a fresh state, unless there is a backup
+        fs::write(&path, "").await.unwrap();
+        assert_eq!(State::load(&path, "chat").await.tg_id, 1);
+        let mut backup = path.clone();
+        backup.set_extension(BACKUP_EXTENSION);
+        fs::remove_file(&backup).await.unwrap();
+        assert_eq!(State::load(&path, "chat").await, State::new("chat"));
+    }
+}
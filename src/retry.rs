@@ -0,0 +1,133 @@
+use serde::Deserialize;
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How the stages retry an operation that failed with a transient error
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of attempts before giving up, including the first one
+    pub(crate) max_attempts: u32,
+    /// Delay before the first retry, in milliseconds
+    pub(crate) initial_delay: u64,
+    /// Upper bound of the delay between attempts, in milliseconds
+    pub(crate) max_delay: u64,
+    /// Factor applied to the delay after each attempt
+    pub(crate) multiplier: f64,
+    /// Fraction of the delay that is randomized, so that retries of different stages spread out
+    pub(crate) jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: 1000,
+            max_delay: 60_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retrying after the given failed attempt, starting at 1, without jitter
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(64) as i32;
+        let delay = self.initial_delay as f64 * self.multiplier.powi(exponent);
+        Duration::from_millis(delay.min(self.max_delay as f64) as u64)
+    }
+
+    /// Waits before the next attempt of `what` and returns `true`, or returns `false` right away
+    /// when the failed attempt was the last one
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut attempt = 0;
+    /// let result = loop {
+    ///     attempt += 1;
+    ///     match operation().await {
+    ///         Err(e) if is_retryable(&e) && policy.wait(attempt, "operation", &e).await => continue,
+    ///         result => break result,
+    ///     }
+    /// };
+    /// ```
+    pub async fn wait(&self, attempt: u32, what: &str, error: &(dyn Display + Sync)) -> bool {
+        if attempt >= self.max_attempts {
+            log::error!("{} failed after {} attempts: {}", what, attempt, error);
+            return false;
+        }
+        let delay = self.with_jitter(self.delay(attempt));
+        log::warn!(
+            "{} failed (attempt {} of {}), retrying in {:?}: {}",
+            what,
+            attempt,
+            self.max_attempts,
+            delay,
+            error
+        );
+        tokio::time::sleep(delay).await;
+        true
+    }
+
+    fn with_jitter(&self, delay: Duration) -> Duration {
+        // Good enough randomness for spreading retries, without pulling in a random crate
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time")
+            .subsec_nanos();
+        let random = nanos as f64 / 1_000_000_000.0 * 2.0 - 1.0;
+        delay.mul_f64((1.0 + self.jitter.clamp(0.0, 1.0) * random).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay: 1,
+            max_delay: 4,
+            multiplier: 2.0,
+            jitter: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_delay_grows_up_to_max() {
+        let policy = policy(10);
+        let delays: Vec<u64> = (1..=5)
+            .map(|attempt| policy.delay(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 4, 4]);
+    }
+
+    #[test]
+    fn test_jitter_stays_in_range() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.with_jitter(Duration::from_millis(1000));
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1500));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_max_attempts() {
+        let policy = policy(3);
+        let mut attempt = 0;
+        let result: Result<(), &str> = loop {
+            attempt += 1;
+            match Err("boom") {
+                Err(e) if policy.wait(attempt, "test", &e).await => continue,
+                result => break result,
+            }
+        };
+        assert_eq!(result, Err("boom"));
+        assert_eq!(attempt, 3);
+    }
+}
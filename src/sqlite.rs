@@ -0,0 +1,380 @@
+use crate::dead_letter::{DeadLetter, DeadLetterStatus};
+use crate::mapping::Mapping;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DATABASE_FILE: &str = "twittergram.db";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS state (
+    chat TEXT PRIMARY KEY,
+    tg_id INTEGER NOT NULL,
+    updated INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS mapping (
+    chat TEXT NOT NULL,
+    tg_id INTEGER NOT NULL,
+    tweet_ids TEXT NOT NULL,
+    media_ids TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    deleted INTEGER NOT NULL DEFAULT 0,
+    edit_date INTEGER,
+    content_hash INTEGER,
+    PRIMARY KEY (chat, tg_id)
+);
+CREATE TABLE IF NOT EXISTS dead_letters (
+    chat TEXT NOT NULL,
+    tg_id INTEGER NOT NULL,
+    stage TEXT NOT NULL,
+    error TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    PRIMARY KEY (chat, tg_id)
+);
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    finished INTEGER,
+    posts INTEGER NOT NULL DEFAULT 0
+);
+";
+
+/// The SQLite database in the data dir holding the state of all the routes, so the history of
+/// the mirror can be queried with plain SQL
+///
+/// The database is small and only written once per post, so the statements run right away on the
+/// calling task. Clones share the same connection.
+#[derive(Clone)]
+pub struct Database {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    pub fn open(data_dir: &str) -> Database {
+        let mut path = PathBuf::from(data_dir);
+        path.push(DATABASE_FILE);
+        Database::with_connection(Connection::open(path).expect("Error opening database"))
+    }
+
+    fn with_connection(connection: Connection) -> Database {
+        connection
+            .execute_batch(SCHEMA)
+            .expect("Error creating database schema");
+        Database {
+            connection: Arc::new(Mutex::new(connection)),
+        }
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection.lock().expect("Database lock")
+    }
+
+    /// The last Telegram message id processed for the chat
+    pub fn last_id(&self, chat: &str) -> Option<i32> {
+        self.connection()
+            .query_row(
+                "SELECT tg_id FROM state WHERE chat = ?1",
+                params![chat],
+                |row| row.get(0),
+            )
+            .optional()
+            .expect("Read state")
+    }
+
+    pub fn save_last_id(&self, chat: &str, tg_id: i32) -> rusqlite::Result<()> {
+        insert_last_id(&self.connection(), chat, tg_id)
+    }
+
+    pub fn mappings(&self, chat: &str) -> Vec<Mapping> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare(
+                "SELECT tg_id, chat, tweet_ids, media_ids, timestamp, deleted, edit_date,
+                 content_hash FROM mapping WHERE chat = ?1 ORDER BY tg_id",
+            )
+            .expect("Read mapping");
+        let rows = statement
+            .query_map(params![chat], mapping_of)
+            .expect("Read mapping");
+        rows.map(|row| row.expect("Read mapping")).collect()
+    }
+
+    pub fn save_mapping(&self, mapping: &Mapping) {
+        insert_mapping(&self.connection(), mapping).expect("Save mapping");
+    }
+
+    pub fn dead_letters(&self, chat: &str) -> Vec<DeadLetter> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare(
+                "SELECT tg_id, chat, stage, error, timestamp, status FROM dead_letters
+                 WHERE chat = ?1 ORDER BY tg_id",
+            )
+            .expect("Read dead letters");
+        let rows = statement
+            .query_map(params![chat], dead_letter_of)
+            .expect("Read dead letters");
+        rows.map(|row| row.expect("Read dead letters")).collect()
+    }
+
+    pub fn save_dead_letter(&self, letter: &DeadLetter) {
+        insert_dead_letter(&self.connection(), letter).expect("Save dead letter");
+    }
+
+    /// Saves at once the progress of a chat kept elsewhere, the entries are saved in order so the
+    /// latest one of a Telegram message wins
+    pub fn import(
+        &self,
+        chat: &str,
+        last_id: Option<i32>,
+        mappings: &[Mapping],
+        letters: &[DeadLetter],
+    ) -> rusqlite::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for mapping in mappings {
+            insert_mapping(&transaction, mapping)?;
+        }
+        for letter in letters {
+            insert_dead_letter(&transaction, letter)?;
+        }
+        if let Some(tg_id) = last_id {
+            insert_last_id(&transaction, chat, tg_id)?;
+        }
+        transaction.commit()
+    }
+
+    /// Whether a state, a mapping or a dead letter is kept for the chat
+    pub fn has_progress(&self, chat: &str) -> bool {
+        self.connection()
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM state WHERE chat = ?1)
+                 OR EXISTS (SELECT 1 FROM mapping WHERE chat = ?1)
+                 OR EXISTS (SELECT 1 FROM dead_letters WHERE chat = ?1)",
+                params![chat],
+                |row| row.get(0),
+            )
+            .expect("Read progress")
+    }
+
+    /// Moves everything kept for a chat to another key
+    pub fn rekey(&self, from: &str, to: &str) -> rusqlite::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for table in ["state", "mapping", "dead_letters", "runs"] {
+            transaction.execute(
+                &format!("UPDATE {} SET chat = ?2 WHERE chat = ?1", table),
+                params![from, to],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// Records the start of a run of the chat, returns the id of the run
+    pub fn start_run(&self, chat: &str) -> i64 {
+        let connection = self.connection();
+        connection
+            .execute(
+                "INSERT INTO runs (chat, started) VALUES (?1, ?2)",
+                params![chat, now()],
+            )
+            .expect("Save run");
+        connection.last_insert_rowid()
+    }
+
+    pub fn count_post(&self, run: i64) {
+        self.connection()
+            .execute(
+                "UPDATE runs SET posts = posts + 1 WHERE id = ?1",
+                params![run],
+            )
+            .expect("Save run");
+    }
+
+    pub fn finish_run(&self, run: i64) {
+        self.connection()
+            .execute(
+                "UPDATE runs SET finished = ?2 WHERE id = ?1",
+                params![run, now()],
+            )
+            .expect("Save run");
+    }
+}
+
+fn insert_last_id(connection: &Connection, chat: &str, tg_id: i32) -> rusqlite::Result<()> {
+    connection
+        .execute(
+            "INSERT INTO state (chat, tg_id, updated) VALUES (?1, ?2, ?3)
+             ON CONFLICT (chat) DO UPDATE SET tg_id = ?2, updated = ?3",
+            params![chat, tg_id, now()],
+        )
+        .map(|_| ())
+}
+
+fn insert_mapping(connection: &Connection, mapping: &Mapping) -> rusqlite::Result<()> {
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO mapping (chat, tg_id, tweet_ids, media_ids, timestamp,
+             deleted, edit_date, content_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                mapping.chat,
+                mapping.tg_id,
+                serde_json::to_string(&mapping.tweet_ids).expect("Serialize tweet ids"),
+                serde_json::to_string(&mapping.media_ids).expect("Serialize media ids"),
+                mapping.timestamp as i64,
+                mapping.deleted,
+                mapping.edit_date,
+                // SQLite integers are signed, the bits of the hash are kept as they are
+                mapping.content_hash.map(|hash| hash as i64),
+            ],
+        )
+        .map(|_| ())
+}
+
+fn insert_dead_letter(connection: &Connection, letter: &DeadLetter) -> rusqlite::Result<()> {
+    let status = serde_json::to_value(letter.status).expect("Serialize status");
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO dead_letters (chat, tg_id, stage, error, timestamp, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                letter.chat,
+                letter.tg_id,
+                letter.stage,
+                letter.error,
+                letter.timestamp as i64,
+                status.as_str(),
+            ],
+        )
+        .map(|_| ())
+}
+
+fn mapping_of(row: &Row) -> rusqlite::Result<Mapping> {
+    Ok(Mapping {
+        tg_id: row.get(0)?,
+        chat: row.get(1)?,
+        tweet_ids: json_column(row, 2)?,
+        media_ids: json_column(row, 3)?,
+        timestamp: row.get::<_, i64>(4)? as u64,
+        deleted: row.get(5)?,
+        edit_date: row.get(6)?,
+        content_hash: row.get::<_, Option<i64>>(7)?.map(|hash| hash as u64),
+    })
+}
+
+fn json_column<T: serde::de::DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(index)?;
+    serde_json::from_str(&text).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+fn dead_letter_of(row: &Row) -> rusqlite::Result<DeadLetter> {
+    let status: String = row.get(5)?;
+    Ok(DeadLetter {
+        tg_id: row.get(0)?,
+        chat: row.get(1)?,
+        stage: row.get(2)?,
+        error: row.get(3)?,
+        timestamp: row.get::<_, i64>(4)? as u64,
+        status: serde_json::from_value(status.into()).unwrap_or(DeadLetterStatus::Failed),
+    })
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time")
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn database() -> Database {
+        Database::with_connection(Connection::open_in_memory().unwrap())
+    }
+
+    #[test]
+    fn test_state_per_chat() {
+        let db = database();
+        assert_eq!(db.last_id("news"), None);
+        db.save_last_id("news", 10).unwrap();
+        db.save_last_id("news", 12).unwrap();
+        db.save_last_id("other", 3).unwrap();
+        assert_eq!(db.last_id("news"), Some(12));
+        assert_eq!(db.last_id("other"), Some(3));
+    }
+
+    #[test]
+    fn test_mapping_and_dead_letters() {
+        let db = database();
+        let mapping = Mapping {
+            tg_id: 5,
+            chat: "news".to_string(),
+            tweet_ids: vec!["1".to_string(), "2".to_string()],
+            media_ids: vec![7],
+            timestamp: 100,
+            deleted: false,
+            edit_date: Some(200),
+            content_hash: Some(u64::MAX),
+        };
+        db.save_mapping(&mapping);
+        db.save_mapping(&Mapping {
+            deleted: true,
+            ..mapping.clone()
+        });
+        assert_eq!(
+            db.mappings("news"),
+            vec![Mapping {
+                deleted: true,
+                ..mapping
+            }]
+        );
+        assert!(db.mappings("other").is_empty());
+
+        let letter = DeadLetter {
+            tg_id: 6,
+            chat: "news".to_string(),
+            stage: "post".to_string(),
+            error: "timeout".to_string(),
+            timestamp: 100,
+            status: DeadLetterStatus::Requeued,
+        };
+        db.save_dead_letter(&letter);
+        assert_eq!(db.dead_letters("news"), vec![letter]);
+    }
+
+    #[test]
+    fn test_rekey() {
+        let db = database();
+        db.save_last_id("news", 10).unwrap();
+        db.start_run("news");
+        assert!(db.has_progress("news"));
+        db.rekey("news", "news/mastodon").unwrap();
+        assert!(!db.has_progress("news"));
+        assert_eq!(db.last_id("news/mastodon"), Some(10));
+    }
+
+    #[test]
+    fn test_run_history() {
+        let db = database();
+        let run = db.start_run("news");
+        db.count_post(run);
+        db.count_post(run);
+        db.finish_run(run);
+        let posts: i64 = db
+            .connection()
+            .query_row(
+                "SELECT posts FROM runs WHERE id = ?1 AND finished IS NOT NULL",
+                params![run],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(posts, 2);
+    }
+}
@@ -0,0 +1,105 @@
+#[cfg(feature = "sqlite")]
+use crate::sqlite::Database;
+use crate::types::{Cfg, StorageKind};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::PathBuf;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Where the state, the mapping and the dead letters of the routes are kept
+#[derive(Clone)]
+pub enum Storage {
+    /// Files in the data dir of each route
+    Files,
+    /// A SQLite database in the data dir, shared by all the routes
+    #[cfg(feature = "sqlite")]
+    Sqlite(Database),
+    /// Reads the progress from the wrapped storage but keeps what a run changes in memory, so a
+    /// dry run leaves it untouched. Only the last message processed is saved, if `save_state`
+    DryRun {
+        storage: Box<Storage>,
+        save_state: bool,
+    },
+}
+
+impl Storage {
+    pub fn open(config: &Cfg) -> Storage {
+        let storage = match config.storage {
+            StorageKind::Files => Storage::Files,
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite => Storage::Sqlite(Database::open(&config.data_dir)),
+            #[cfg(not(feature = "sqlite"))]
+            StorageKind::Sqlite => {
+                panic!("storage=\"sqlite\" requires building with --features sqlite")
+            }
+        };
+        match &config.dry_run {
+            Some(dry_run) => Storage::DryRun {
+                storage: Box::new(storage),
+                save_state: dry_run.save_state,
+            },
+            None => storage,
+        }
+    }
+
+    /// The storage the progress is read from
+    pub fn source(&self) -> &Storage {
+        match self {
+            Storage::DryRun { storage, .. } => storage.source(),
+            storage => storage,
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self, Storage::DryRun { .. })
+    }
+}
+
+/// Opens a file of JSON lines in the data dir for appending, and reads its entries
+///
+/// Invalid lines, e.g. the partial last line left by a crash while appending, are skipped.
+pub async fn open_lines<T: DeserializeOwned>(data_dir: &str, name: &str) -> (File, Vec<T>) {
+    let mut path = PathBuf::from(data_dir);
+    path.push(name);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path.as_path())
+        .await
+        .unwrap_or_else(|e| panic!("Error opening {} file: {}", name, e));
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .await
+        .unwrap_or_else(|e| panic!("Error reading {} file: {}", name, e));
+    if !content.is_empty() && !content.ends_with('\n') {
+        // The next entry goes on its own line, not after the partial one
+        file.write_all(b"\n")
+            .await
+            .unwrap_or_else(|e| panic!("Error repairing {} file: {}", name, e));
+    }
+
+    let entries = content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Ignoring invalid {} entry {:?}: {}", name, line, e);
+                None
+            }
+        })
+        .collect();
+    (file, entries)
+}
+
+/// Appends an entry to a file of JSON lines and syncs it to disk
+pub async fn append_line<T: Serialize>(file: &mut File, entry: &T) {
+    let mut line = serde_json::to_string(entry).expect("Serialize entry");
+    line.push('\n');
+    file.write_all(line.as_bytes()).await.expect("Save entry");
+    file.sync_data().await.expect("Sync entry");
+}
@@ -0,0 +1,110 @@
+use crate::mapping::MappingStore;
+use crate::telegram::types::{TelegramClient, MAX_MESSAGES_PER_REQUEST};
+use crate::types::{Route, Runnable, Source};
+use crate::Cfg;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+/// Finds mirrored Telegram messages that no longer exist in the chat
+///
+/// Re-scans the `window` most recently mirrored messages and emits the ids of the ones that were
+/// deleted. A post made of an album is mapped by its oldest message, so it is only considered
+/// deleted when that message is.
+pub struct DeletionScanner<T: TelegramClient> {
+    client: T,
+    chat_name: String,
+    mappings: MappingStore,
+    window: usize,
+    sender: Option<Sender<i32>>,
+}
+
+impl<T: TelegramClient> DeletionScanner<T> {
+    pub(crate) fn new(client: T, config: &Cfg, route: &Route, mappings: MappingStore) -> Self {
+        DeletionScanner {
+            client,
+            chat_name: route.chat_name.clone(),
+            mappings,
+            window: config.sync.window,
+            sender: None,
+        }
+    }
+}
+
+impl<T: TelegramClient> Source<i32> for DeletionScanner<T> {
+    fn set_output(&mut self, output: Sender<i32>) {
+        self.sender = Some(output);
+    }
+}
+
+impl<T: TelegramClient> Runnable for DeletionScanner<T> {
+    fn run(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let chat = match self.client.resolve_username(&self.chat_name).await {
+                Ok(Some(c)) => c,
+                _ => {
+                    panic!("Chat {} could not be found", &self.chat_name);
+                }
+            };
+            let ids = window_ids(&self.mappings, self.window).await;
+            for batch in ids.chunks(MAX_MESSAGES_PER_REQUEST) {
+                let messages = match self.client.get_messages_by_id(&chat, batch).await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        log::warn!("[Deletions] Error checking messages {:?}: {}", batch, e);
+                        continue;
+                    }
+                };
+                for id in missing(batch, messages) {
+                    log::info!("Telegram post {} was deleted", id);
+                    self.sender.as_ref().unwrap().send(id).await.expect("send");
+                }
+            }
+        })
+    }
+}
+
+/// The mirrored messages to check, the most recent ones whose tweets are still up
+async fn window_ids(mappings: &MappingStore, window: usize) -> Vec<i32> {
+    mappings
+        .latest(window)
+        .await
+        .into_iter()
+        .filter(|m| !m.deleted)
+        .map(|m| m.tg_id)
+        .collect()
+}
+
+/// The ids of a batch whose message was not found
+fn missing<M>(batch: &[i32], messages: Vec<Option<M>>) -> Vec<i32> {
+    batch
+        .iter()
+        .zip(messages)
+        .filter(|(_, message)| message.is_none())
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapping::Mapping;
+    use crate::storage::Storage;
+    use crate::types::Post;
+    use crate::util::TempDir;
+
+    #[tokio::test]
+    async fn test_rescans_the_window() {
+        let dir = TempDir::new("deletions");
+        let mappings = MappingStore::open(&Storage::Files, dir.path(), "chat").await;
+        for id in 1..=5 {
+            let mut post = Post::new(id, String::new());
+            post.set_tweet_ids(vec![format!("{}0", id)]);
+            mappings.record(Mapping::from_post(&post, "chat")).await;
+        }
+        mappings.mark_deleted(4).await;
+
+        let ids = window_ids(&mappings, 3).await;
+        assert_eq!(ids, vec![5, 3]);
+        assert_eq!(missing(&ids, vec![Some(()), None]), vec![3]);
+    }
+}
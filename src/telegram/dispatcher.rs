@@ -0,0 +1,86 @@
+use crate::telegram::types::{TelegramClient, TelegramMessage, TelegramUpdate};
+use crate::types::Runnable;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// How long to wait before listening again after an error
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Receives the live updates of the shared Telegram connection and hands them to the routes
+/// subscribed to their chat
+pub struct UpdateDispatcher<T: TelegramClient> {
+    client: T,
+    subscribers: Vec<(String, Sender<TelegramUpdate<T::M>>)>,
+}
+
+impl<T: TelegramClient> UpdateDispatcher<T> {
+    pub fn new(client: T) -> Self {
+        UpdateDispatcher {
+            client,
+            subscribers: vec![],
+        }
+    }
+
+    /// Returns the updates of the given chat
+    pub fn subscribe(&mut self, chat_name: &str) -> Receiver<TelegramUpdate<T::M>> {
+        let (sender, receiver) = mpsc::channel(1000);
+        self.subscribers.push((chat_name.to_string(), sender));
+        receiver
+    }
+}
+
+impl<T: TelegramClient> Runnable for UpdateDispatcher<T> {
+    fn run(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut subscribers = vec![];
+            for (chat_name, sender) in self.subscribers {
+                match self.client.resolve_username(&chat_name).await {
+                    Ok(Some(chat)) => subscribers.push((chat.id(), chat_name, sender)),
+                    _ => panic!("Chat {} could not be found", chat_name),
+                }
+            }
+
+            loop {
+                let update = match self.client.next_update().await {
+                    Ok(Some(update)) => update,
+                    Ok(None) => {
+                        log::warn!("Telegram client disconnected, stop listening");
+                        break;
+                    }
+                    Err(e) => {
+                        log::warn!("[Dispatcher] Error receiving updates: {}", e);
+                        sleep(RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                let chat_id = match &update {
+                    TelegramUpdate::NewMessage(msg) | TelegramUpdate::MessageEdited(msg) => {
+                        msg.chat_id()
+                    }
+                    TelegramUpdate::MessageDeleted(Some(channel), _) => *channel,
+                    _ => continue,
+                };
+                let mut stopped = vec![];
+                for (index, (id, chat_name, sender)) in subscribers.iter().enumerate() {
+                    if *id != chat_id {
+                        continue;
+                    }
+                    if let Err(e) = sender.send(update.clone()).await {
+                        log::warn!(
+                            "[Dispatcher] Error handing update to {}, dropping it: {}",
+                            chat_name,
+                            e
+                        );
+                        stopped.push(index);
+                    }
+                }
+                for index in stopped.into_iter().rev() {
+                    subscribers.remove(index);
+                }
+            }
+        })
+    }
+}
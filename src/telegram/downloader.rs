@@ -1,7 +1,10 @@
 use std::path::PathBuf;
 
+use crate::dead_letter::DeadLetters;
+use crate::journal::{Journal, Step};
+use crate::retry::RetryPolicy;
 use crate::telegram::types::TelegramClient;
-use crate::types::{Attachment, Cfg};
+use crate::types::{Attachment, Route};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
 
@@ -13,18 +16,65 @@ pub struct TelegramDownloader<T: TelegramClient> {
     receiver: Option<Receiver<Post>>,
     sender: Option<Sender<Post>>,
     path: String,
+    retry: RetryPolicy,
+    dead_letters: DeadLetters,
+    journal: Journal,
 }
 
 impl<T: TelegramClient> TelegramDownloader<T> {
-    pub(crate) fn new(client: T, cfg: &Cfg) -> Self {
+    pub(crate) fn new(
+        client: T,
+        route: &Route,
+        retry: RetryPolicy,
+        dead_letters: DeadLetters,
+        journal: Journal,
+    ) -> Self {
         TelegramDownloader {
             client,
             receiver: None,
             sender: None,
-            path: cfg.data_dir.clone(),
+            path: route.data_dir.clone(),
+            retry,
+            dead_letters,
+            journal,
         }
     }
 
+    /// Downloads the media of the post, retrying each one according to the retry policy. The
+    /// media of a resumed post that were already downloaded are kept
+    async fn download(&self, msg: &Post) -> Result<(), std::io::Error> {
+        let downloaded = match self.journal.resume(msg).await {
+            Some(entry) if entry.step >= Step::Downloaded => msg
+                .attachments()
+                .iter()
+                .enumerate()
+                .all(|(i, attachment)| self.get_save_path(attachment, msg.id(), i).exists()),
+            _ => false,
+        };
+        if downloaded {
+            log::info!("Media of telegram post {} already downloaded", msg.id());
+            return Ok(());
+        }
+
+        for (i, attachment) in msg.attachments().iter().enumerate() {
+            let path = self.get_save_path(attachment, msg.id(), i);
+            let what = format!("[Downloader] Download of media {} of {}", i, msg.id());
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                match self
+                    .client
+                    .download_media(attachment.tg_media(), path.as_path())
+                    .await
+                {
+                    Err(e) if self.retry.wait(attempt, &what, &e).await => continue,
+                    result => break result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_save_path(&self, attachment: &Attachment, msg_id: i32, index: usize) -> PathBuf {
         let attachment_mime = &attachment.mime();
         let extension = mime_guess::get_mime_extensions(attachment_mime)
@@ -61,15 +111,11 @@ impl<T: TelegramClient> Runnable for TelegramDownloader<T> {
                     }
                     Some(msg) => {
                         log::info!("Received {:?}", msg.id());
-                        for i in 0..msg.attachments().len() {
-                            let attachment = &msg.attachments()[i];
-                            let path = self.get_save_path(attachment, msg.id(), i);
-
-                            self.client
-                                .download_media(attachment.tg_media(), path.as_path())
-                                .await
-                                .expect("Error downloading message");
+                        if let Err(e) = self.download(&msg).await {
+                            self.dead_letters.add(msg.id(), "download", &e).await;
+                            continue;
                         }
+                        self.journal.record(&msg, Step::Downloaded, |_| {}).await;
                         self.sender
                             .as_ref()
                             .unwrap()
@@ -1,196 +1,759 @@
-use crate::telegram::types::{TelegramClient, TelegramMessage, TelegramMessageIter};
-use crate::types::{Attachment, Post, Runnable, Source};
+use crate::dead_letter::{DeadLetterStatus, DeadLetters};
+use crate::filter::Filter;
+use crate::journal::{Journal, Step};
+use crate::mapping::MappingStore;
+use crate::telegram::types::{
+    TelegramClient, TelegramMessage, TelegramMessageIter, TelegramUpdate, MAX_MESSAGES_PER_REQUEST,
+};
+use crate::types::{Attachment, EditPolicy, Post, Route, Runnable, Source};
 use crate::Cfg;
-use std::collections::vec_deque::{Iter, VecDeque};
-use tokio::sync::mpsc::Sender;
+use grammers_client::client::messages::InvocationError;
+use grammers_client::types::Chat;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
 
-const IGNORE: &str = "#tgonly";
+/// Maximum number of messages in a Telegram album
+const MAX_ALBUM_SIZE: i32 = 10;
+
+/// How far past the oldest part of an album the history is scanned for more of its parts
+const ALBUM_SCAN_WINDOW: i32 = 2 * MAX_ALBUM_SIZE;
+
+/// How long to wait for more parts of an album received as live updates
+const ALBUM_TIMEOUT: Duration = Duration::from_secs(3);
+
+type PendingAlbums<M> = HashMap<i64, (Album<M>, Instant)>;
 
 pub struct TelegramGenerator<T: TelegramClient> {
     client: T,
     chat_name: String,
+    filter: Filter,
     last_id: i32,
     size: i32,
+    mappings: MappingStore,
+    dead_letters: DeadLetters,
+    journal: Journal,
+    /// The progress of the other destinations of the route, whose posts to resume are
+    /// mirrored as well
+    others: Vec<(Journal, DeadLetters)>,
+    edits: EditPolicy,
+    window: usize,
+    updates: Option<Receiver<TelegramUpdate<T::M>>>,
     sender: Option<Sender<Post>>,
+    deletion_sender: Option<Sender<i32>>,
 }
 
 impl<T: TelegramClient> TelegramGenerator<T> {
-    pub(crate) fn new(client: T, config: &Cfg, last_id: i32) -> Self {
+    /// Mirrors the messages since `last_id` then, if `updates` is given, keeps emitting the
+    /// messages received as updates. The posts interrupted by a previous run and the dead
+    /// letters requeued are mirrored first
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        client: T,
+        config: &Cfg,
+        route: &Route,
+        last_id: i32,
+        mappings: MappingStore,
+        dead_letters: DeadLetters,
+        journal: Journal,
+        updates: Option<Receiver<TelegramUpdate<T::M>>>,
+    ) -> Self {
         TelegramGenerator {
             client,
-            chat_name: config.telegram.chat_name.clone(),
+            chat_name: route.chat_name.clone(),
+            filter: Filter::of_route(route),
             last_id,
             size: config.max_messages,
+            mappings,
+            dead_letters,
+            journal,
+            others: vec![],
+            edits: config.sync.edits,
+            window: config.sync.window,
+            updates,
             sender: None,
+            deletion_sender: None,
         }
     }
-}
 
-impl<T: TelegramClient> Source<Post> for TelegramGenerator<T> {
-    fn set_output(&mut self, output: Sender<Post>) {
-        self.sender = Some(output);
+    /// Emits a new post, unless it is filtered out or was already emitted
+    async fn emit(&mut self, post: Post) {
+        if self.filter.accepts(&post) && post.id() > self.last_id {
+            log::info!("Emitting telegram post {:?}", post);
+            self.last_id = post.id();
+            // A post interrupted by the previous run goes on from where it stopped
+            if self.journal.resume(&post).await.is_none() {
+                self.journal.record(&post, Step::Fetched, |_| {}).await;
+            }
+            self.sender
+                .as_ref()
+                .unwrap()
+                .send(post)
+                .await
+                .expect("send");
+        }
     }
-}
 
-struct FixedDeque<T> {
-    queue: VecDeque<T>,
-    size: i32,
-}
+    /// Follows the live updates of the chat until the client is disconnected. Albums are
+    /// buffered until a different message arrives or no part is received for a while
+    async fn listen(&mut self, chat: &Chat, mut updates: Receiver<TelegramUpdate<T::M>>) {
+        log::info!("Listening to updates of {}", self.chat_name);
+        let mut albums: PendingAlbums<T::M> = HashMap::new();
+        loop {
+            let next_flush = albums.values().map(|(_, deadline)| *deadline).min();
+            let update = match next_flush {
+                None => updates.recv().await,
+                Some(deadline) => match timeout_at(deadline, updates.recv()).await {
+                    Ok(update) => update,
+                    Err(_) => {
+                        self.flush_albums(&mut albums, |_, deadline| deadline <= Instant::now())
+                            .await;
+                        continue;
+                    }
+                },
+            };
 
-impl<T> FixedDeque<T> {
-    fn iterator(&mut self) -> Iter<'_, T> {
-        self.queue.iter()
+            match update {
+                Some(TelegramUpdate::NewMessage(msg)) => {
+                    match msg.grouped_id() {
+                        Some(group) => {
+                            let (album, deadline) = albums
+                                .entry(group)
+                                .or_insert_with(|| (Album::new(group), Instant::now()));
+                            album.add_item(msg);
+                            *deadline = Instant::now() + ALBUM_TIMEOUT;
+                        }
+                        None => {
+                            // Albums are sent at once, any older one is complete
+                            let id = msg.id();
+                            self.flush_albums(&mut albums, |album, _| album.get_msg_id() < id)
+                                .await;
+                            self.emit(Post::from_message(&msg)).await;
+                        }
+                    }
+                }
+                Some(TelegramUpdate::MessageEdited(msg)) => {
+                    if self.edits != EditPolicy::Ignore {
+                        let mut post = match msg.grouped_id() {
+                            None => Post::from_message(&msg),
+                            Some(group) => self.album_of(chat, msg, group).await,
+                        };
+                        if self.filter.accepts(&post) {
+                            log::info!("Emitting edited telegram post {:?}", post.id());
+                            post.set_edited(true);
+                            self.sender
+                                .as_ref()
+                                .unwrap()
+                                .send(post)
+                                .await
+                                .expect("send");
+                        }
+                    }
+                }
+                Some(TelegramUpdate::MessageDeleted(_, ids)) => {
+                    if let Some(deletions) = self.deletion_sender.as_ref() {
+                        for id in ids {
+                            deletions.send(id).await.expect("send");
+                        }
+                    }
+                }
+                Some(TelegramUpdate::Other) => {}
+                None => break,
+            }
+        }
+        self.flush_albums(&mut albums, |_, _| true).await;
     }
-    fn new(size: i32) -> Self {
-        FixedDeque {
-            queue: VecDeque::new(),
-            size,
+
+    /// Closes and emits, oldest first, the pending albums that match the predicate
+    async fn flush_albums<F: Fn(&Album<T::M>, Instant) -> bool>(
+        &mut self,
+        albums: &mut PendingAlbums<T::M>,
+        predicate: F,
+    ) {
+        let mut groups: Vec<(i32, i64)> = albums
+            .iter()
+            .filter(|(_, (album, deadline))| predicate(album, *deadline))
+            .map(|(group, (album, _))| (album.get_msg_id(), *group))
+            .collect();
+        groups.sort();
+        for (_, group) in groups {
+            if let Some((album, _)) = albums.remove(&group) {
+                self.emit(album.close()).await;
+            }
         }
     }
 
-    fn push(&mut self, element: T) {
-        self.queue.push_front(element);
-        if self.queue.len() > self.size as usize {
-            self.queue.pop_back();
+    /// Re-fetches the most recently mirrored messages, with all the parts of the albums, and
+    /// returns the ones edited after they were mirrored, flagged as edits
+    async fn edited_posts(&self, chat: &Chat) -> Vec<Post> {
+        let mirrored: Vec<_> = self
+            .mappings
+            .latest(self.window)
+            .await
+            .into_iter()
+            .filter(|m| !m.deleted)
+            .collect();
+
+        let mut posts = vec![];
+        for batch in mirrored.chunks(MAX_MESSAGES_PER_REQUEST) {
+            let ids: Vec<i32> = batch.iter().map(|m| m.tg_id).collect();
+            let messages = match self.client.get_messages_by_id(chat, &ids).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    log::warn!("[Generator] Error checking edits of {:?}: {}", ids, e);
+                    continue;
+                }
+            };
+            for (mapping, message) in batch.iter().zip(messages) {
+                // An album is mapped by its first part, but any of them may have been edited
+                let mut post = match message {
+                    Some(msg) => match msg.grouped_id() {
+                        None => Post::from_message(&msg),
+                        Some(group) => self.album_of(chat, msg, group).await,
+                    },
+                    None => continue,
+                };
+                if post.edit_date() <= mapping.edit_date {
+                    continue;
+                }
+                if self.filter.accepts(&post) {
+                    post.set_edited(true);
+                    posts.push(post);
+                }
+            }
         }
+        posts
+    }
+
+    /// Mirrors as well the posts to resume of the other destinations of the route
+    pub fn resume_for(&mut self, journal: Journal, dead_letters: DeadLetters) {
+        self.others.push((journal, dead_letters));
+    }
+
+    /// The journal and dead letters of every destination
+    fn progress(&self) -> impl Iterator<Item = (&Journal, &DeadLetters)> {
+        std::iter::once((&self.journal, &self.dead_letters)).chain(
+            self.others
+                .iter()
+                .map(|(journal, dead_letters)| (journal, dead_letters)),
+        )
+    }
+
+    /// Re-fetches the posts to mirror again, even if they are older than `last_id`: the ones
+    /// interrupted by the previous run, unless they were given up, and the requeued dead letters,
+    /// of every destination
+    async fn resumed_posts(&self, chat: &Chat) -> Vec<Post> {
+        let resumed = resumed_ids(self.progress(), self.last_id).await;
+
+        let mut posts = vec![];
+        for ids in resumed.chunks(MAX_MESSAGES_PER_REQUEST) {
+            let messages = match self.client.get_messages_by_id(chat, ids).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    log::warn!(
+                        "[Generator] Error fetching posts to resume {:?}: {}",
+                        ids,
+                        e
+                    );
+                    continue;
+                }
+            };
+            for (id, message) in ids.iter().zip(messages) {
+                let msg = match message {
+                    Some(m) => m,
+                    None => {
+                        log::warn!("Telegram post {} to resume no longer exists", id);
+                        let gone = Post::new(*id, String::new());
+                        for (journal, dead_letters) in self.progress() {
+                            dead_letters.resolve(*id).await;
+                            journal.record(&gone, Step::Done, |_| {}).await;
+                        }
+                        continue;
+                    }
+                };
+                let post = match msg.grouped_id() {
+                    None => Post::from_message(&msg),
+                    Some(group) => self.album_of(chat, msg, group).await,
+                };
+                if self.filter.accepts(&post) {
+                    posts.push(post);
+                }
+            }
+        }
+        posts
+    }
+
+    /// Rebuilds the album the given message is part of, e.g. the edited part, which may not be
+    /// the first one whose id the album takes
+    async fn album_of(&self, chat: &Chat, part: T::M, group: i64) -> Post {
+        let ids = album_window(part.id());
+        let around = match self.client.get_messages_by_id(chat, &ids).await {
+            Ok(messages) => messages.into_iter().flatten().collect(),
+            Err(e) => {
+                log::warn!("[Generator] Error fetching album of {}: {}", part.id(), e);
+                vec![]
+            }
+        };
+        album_from(part, around, group)
+    }
+}
+
+impl<T: TelegramClient> Source<i32> for TelegramGenerator<T> {
+    /// In daemon mode, receives the ids of the deleted messages
+    fn set_output(&mut self, output: Sender<i32>) {
+        self.deletion_sender = Some(output);
+    }
+}
+
+impl<T: TelegramClient> Source<Post> for TelegramGenerator<T> {
+    fn set_output(&mut self, output: Sender<Post>) {
+        self.sender = Some(output);
     }
 }
 
 struct Album<M: TelegramMessage> {
     items: Vec<M>,
-    id: Option<i64>,
+    group: i64,
 }
 
 impl<M: TelegramMessage> Album<M> {
-    fn new() -> Self {
+    fn new(group: i64) -> Self {
         Album {
             items: vec![],
-            id: None,
+            group,
         }
     }
 
-    fn start(&mut self, id: Option<i64>) {
-        self.id = id;
-    }
-
-    fn close(&mut self) -> Post {
-        let text = self
-            .items
-            .iter()
-            .find(|m| !m.text().is_empty())
-            .map(|m| m.text())
-            .unwrap_or("");
-        let mut post = Post::new(self.items.last().unwrap().id(), text.to_string());
+    fn close(mut self) -> Post {
+        // Newest first, the post takes the id of the oldest message
+        self.items.sort_by_key(|m| -m.id());
+        let caption = self.items.iter().find(|m| !m.text().is_empty());
+        let text = caption.map(|m| m.text()).unwrap_or("");
+        let mut post = Post::new(self.get_msg_id(), text.to_string());
+        post.set_origin(self.items.last().unwrap());
+        // Edited when any of its parts was
+        post.set_edit_date(self.items.iter().filter_map(|m| m.edit_date()).max());
+        // The formatting goes with the caption, whichever part of the album it is on
+        post.set_entities(caption.map(|m| m.entities()).unwrap_or_default());
+        post.set_reply_to(self.items.iter().find_map(|m| m.reply_to_message_id()));
         self.items
             .iter()
             .rev()
-            .for_each(|m| post.add_tg_attachment(Attachment::new(m.media().unwrap())));
-        self.items.clear();
-        self.id = None;
+            .filter_map(|m| m.media())
+            .for_each(|media| post.add_tg_attachment(Attachment::new(media)));
         post
     }
 
-    fn get_group(&self) -> i64 {
-        self.id.unwrap()
-    }
-
+    /// The id of the oldest message of the album
     fn get_msg_id(&self) -> i32 {
-        self.items.last().unwrap().id()
+        self.items.iter().map(|m| m.id()).min().unwrap()
     }
 
     fn add_item(&mut self, m: M) {
         self.items.push(m);
     }
-    fn is_empty(&self) -> bool {
-        self.items.is_empty()
+}
+
+/// Scans the history, newest first, down to `last_id` and returns the posts to mirror, oldest
+/// first. At most `size` posts are returned, the oldest ones.
+///
+/// Parts of an album may be interleaved with other messages, so albums are collected per group
+/// and only closed once the scan is far enough from their oldest part.
+async fn scan<M: TelegramMessage, I: TelegramMessageIter<M>>(
+    messages: &mut I,
+    last_id: i32,
+    size: i32,
+    filter: &Filter,
+) -> Result<Vec<Post>, InvocationError> {
+    let mut posts = vec![];
+    let mut albums: HashMap<i64, Album<M>> = HashMap::new();
+
+    while let Some(msg) = messages.next().await? {
+        let complete: Vec<i64> = albums
+            .values()
+            .filter(|album| album.get_msg_id() - ALBUM_SCAN_WINDOW > msg.id())
+            .map(|album| album.group)
+            .collect();
+        for group in complete {
+            posts.push(albums.remove(&group).unwrap().close());
+        }
+
+        // Older messages were already processed, unless they complete an album
+        if msg.id() <= last_id && albums.is_empty() {
+            break;
+        }
+
+        match msg.grouped_id() {
+            Some(group) => match albums.get_mut(&group) {
+                Some(album) => album.add_item(msg),
+                None if msg.id() > last_id => {
+                    let mut album = Album::new(group);
+                    album.add_item(msg);
+                    albums.insert(group, album);
+                }
+                None => {}
+            },
+            None if msg.id() > last_id => posts.push(Post::from_message(&msg)),
+            None => {}
+        }
     }
+    posts.extend(albums.into_values().map(Album::close));
+
+    // An album with parts at or before last_id was already mirrored
+    posts.retain(|post| post.id() > last_id && filter.accepts(post));
+    posts.sort_by_key(Post::id);
+    posts.truncate(size.max(0) as usize);
+    Ok(posts)
 }
 
 impl<T: TelegramClient> Runnable for TelegramGenerator<T> {
-    fn run(self) -> JoinHandle<()> {
+    fn run(mut self) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let chat = match self.client.resolve_username(&*self.chat_name).await {
+            let chat = match self.client.resolve_username(&self.chat_name).await {
                 Ok(Some(c)) => c,
                 _ => {
-                    panic!("Chat {} could not be found", &*self.chat_name);
+                    panic!("Chat {} could not be found", &self.chat_name);
                 }
             };
-            let mut messages = self.client.iter_messages(&chat);
-            let mut album: Album<_> = Album::new();
-            let mut temp_messages = FixedDeque::new(self.size);
-
-            loop {
-                match messages.next().await {
-                    Ok(Some(msg)) => {
-                        if msg.id() <= self.last_id
-                            && (album.is_empty() || album.get_msg_id() <= self.last_id)
-                        {
-                            break;
-                        }
+            for post in self.resumed_posts(&chat).await {
+                log::info!("Emitting resumed telegram post {:?}", post.id());
+                self.sender
+                    .as_ref()
+                    .unwrap()
+                    .send(post)
+                    .await
+                    .expect("send");
+            }
 
-                        match msg.grouped_id() {
-                            None if album.is_empty() => {
-                                // No opened album, simply post the message
-                                let post = Post::from_message(&msg);
-
-                                if post.validate(IGNORE) && msg.id() > self.last_id {
-                                    temp_messages.push(post);
-                                }
-                            }
-                            None => {
-                                // Assumes album is finished, close it and post ir
-                                // TODO: support interleaving of different albums and single messages
-                                let album_post = album.close();
-                                if album_post.validate(IGNORE) {
-                                    temp_messages.push(album_post);
-                                }
-
-                                // Post current message
-                                let post = Post::from_message(&msg);
-
-                                if post.validate(IGNORE) && msg.id() > self.last_id {
-                                    temp_messages.push(post);
-                                }
-                            }
-                            Some(_) if album.is_empty() => {
-                                // Message is part of an album, start tracking it
-                                album.start(msg.grouped_id());
-                                // Todo move to start
-                                album.add_item(msg);
-                            }
-                            Some(g) if g == album.get_group() => {
-                                // Message is part of a already tracked album
-                                album.add_item(msg);
-                            }
-                            Some(_) => {
-                                // Message is part of a different album; close current and start new
-                                let album_post = album.close();
-                                if album_post.validate(IGNORE) {
-                                    temp_messages.push(album_post);
-                                }
-
-                                album.start(msg.grouped_id());
-                                album.add_item(msg);
-                            }
-                        };
-                    }
-                    Ok(None) => break,
-                    Err(e) => {
-                        panic!("{}", e)
-                    }
+            let mut messages = self.client.iter_messages(&chat);
+            let posts = match scan(&mut messages, self.last_id, self.size, &self.filter).await {
+                Ok(posts) => posts,
+                Err(e) => {
+                    panic!("{}", e)
                 }
+            };
+
+            for post in posts {
+                self.emit(post).await;
             }
 
-            for message in temp_messages.iterator() {
-                log::info!("Emitting telegram post {:?}", message);
-                match self.sender.as_ref().unwrap().send(message.clone()).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        panic!("{}", e)
-                    }
+            if self.edits != EditPolicy::Ignore {
+                for post in self.edited_posts(&chat).await {
+                    log::info!("Emitting edited telegram post {:?}", post.id());
+                    self.sender
+                        .as_ref()
+                        .unwrap()
+                        .send(post)
+                        .await
+                        .expect("send");
                 }
             }
+
+            if let Some(updates) = self.updates.take() {
+                self.listen(&chat, updates).await;
+            }
         })
     }
 }
+
+/// The ids around a part of an album where its other parts may be, older and newer
+fn album_window(id: i32) -> Vec<i32> {
+    (id - ALBUM_SCAN_WINDOW..=id + ALBUM_SCAN_WINDOW)
+        .filter(|other| *other > 0 && *other != id)
+        .collect()
+}
+
+/// Rebuilds an album from one of its parts and the messages around it
+fn album_from<M: TelegramMessage>(part: M, around: Vec<M>, group: i64) -> Post {
+    let mut album = Album::new(group);
+    around
+        .into_iter()
+        .filter(|m| m.grouped_id() == Some(group))
+        .for_each(|m| album.add_item(m));
+    album.add_item(part);
+    album.close()
+}
+
+/// The ids of the posts to mirror again, in order, from the progress of every destination
+async fn resumed_ids<'a>(
+    progress: impl Iterator<Item = (&'a Journal, &'a DeadLetters)>,
+    last_id: i32,
+) -> Vec<i32> {
+    let mut resumed: Vec<i32> = vec![];
+    for (journal, dead_letters) in progress {
+        let failed: Vec<i32> = dead_letters
+            .with_status(DeadLetterStatus::Failed)
+            .await
+            .iter()
+            .map(|letter| letter.tg_id)
+            .collect();
+        resumed.extend(
+            journal
+                .pending()
+                .await
+                .into_iter()
+                // The newer ones are found again when scanning the history
+                .filter(|id| *id <= last_id && !failed.contains(id)),
+        );
+        let requeued = dead_letters.with_status(DeadLetterStatus::Requeued).await;
+        resumed.extend(requeued.iter().map(|letter| letter.tg_id));
+    }
+    resumed.sort();
+    resumed.dedup();
+    resumed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::telegram::types::{Entity, EntityKind, ForwardOrigin};
+    use crate::util::TempDir;
+    use async_trait::async_trait;
+    use grammers_client::types::Media;
+
+    #[derive(Clone)]
+    struct FakeMessage {
+        id: i32,
+        text: String,
+        grouped_id: Option<i64>,
+        edit_date: Option<i64>,
+    }
+
+    impl TelegramMessage for FakeMessage {
+        fn id(&self) -> i32 {
+            self.id
+        }
+        fn chat_id(&self) -> i64 {
+            1
+        }
+        fn text(&self) -> &str {
+            &self.text
+        }
+        fn grouped_id(&self) -> Option<i64> {
+            self.grouped_id
+        }
+        fn media(&self) -> Option<Media> {
+            None
+        }
+        fn reply_to_message_id(&self) -> Option<i32> {
+            None
+        }
+        fn date(&self) -> i64 {
+            0
+        }
+        fn post_author(&self) -> Option<&str> {
+            None
+        }
+        fn forward(&self) -> Option<ForwardOrigin> {
+            None
+        }
+        /// The whole text is bold
+        fn entities(&self) -> Vec<Entity> {
+            if self.text.is_empty() {
+                return vec![];
+            }
+            vec![Entity {
+                offset: 0,
+                length: self.text.encode_utf16().count(),
+                kind: EntityKind::Bold,
+            }]
+        }
+        fn edit_date(&self) -> Option<i64> {
+            self.edit_date
+        }
+    }
+
+    /// Returns the messages newest first, like Telegram does
+    struct FakeIter {
+        messages: Vec<FakeMessage>,
+    }
+
+    impl FakeIter {
+        /// Builds the history from messages given oldest first
+        fn new(messages: Vec<(i32, &str, Option<i64>)>) -> Self {
+            FakeIter {
+                messages: messages
+                    .into_iter()
+                    .map(|(id, text, grouped_id)| FakeMessage {
+                        id,
+                        text: text.to_string(),
+                        grouped_id,
+                        edit_date: None,
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TelegramMessageIter<FakeMessage> for FakeIter {
+        async fn total(&mut self) -> Result<usize, InvocationError> {
+            Ok(self.messages.len())
+        }
+
+        async fn next(&mut self) -> Result<Option<FakeMessage>, InvocationError> {
+            Ok(self.messages.pop())
+        }
+    }
+
+    fn filter() -> Filter {
+        Filter::new("#tgonly", &[])
+    }
+
+    fn summary(posts: &[Post]) -> Vec<(i32, &str)> {
+        posts.iter().map(|p| (p.id(), p.text())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_single_messages_oldest_first() {
+        let mut iter = FakeIter::new(vec![(1, "a", None), (2, "b", None), (3, "c", None)]);
+        let posts = scan(&mut iter, -1, 10, &filter()).await.unwrap();
+        assert_eq!(summary(&posts), vec![(1, "a"), (2, "b"), (3, "c")]);
+    }
+
+    #[tokio::test]
+    async fn test_stops_at_last_id() {
+        let mut iter = FakeIter::new(vec![(1, "a", None), (2, "b", None), (3, "c", None)]);
+        let posts = scan(&mut iter, 2, 10, &filter()).await.unwrap();
+        assert_eq!(summary(&posts), vec![(3, "c")]);
+        assert_eq!(iter.messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_album_is_a_single_post() {
+        let mut iter = FakeIter::new(vec![
+            (1, "before", None),
+            (2, "caption", Some(7)),
+            (3, "", Some(7)),
+            (4, "", Some(7)),
+            (5, "after", None),
+        ]);
+        let posts = scan(&mut iter, -1, 10, &filter()).await.unwrap();
+        assert_eq!(
+            summary(&posts),
+            vec![(1, "before"), (2, "caption"), (5, "after")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_album_caption_on_a_later_part() {
+        let mut iter = FakeIter::new(vec![
+            (1, "", Some(7)),
+            (2, "caption", Some(7)),
+            (3, "", Some(7)),
+        ]);
+        let posts = scan(&mut iter, -1, 10, &filter()).await.unwrap();
+        assert_eq!(summary(&posts), vec![(1, "caption")]);
+        assert_eq!(
+            posts[0].entities(),
+            &vec![Entity {
+                offset: 0,
+                length: 7,
+                kind: EntityKind::Bold,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_album_of_edited_later_part() {
+        let message = |id, text: &str, grouped_id| FakeMessage {
+            id,
+            text: text.to_string(),
+            grouped_id,
+            edit_date: None,
+        };
+        let ids = album_window(3);
+        assert!(ids.contains(&1) && ids.contains(&12) && !ids.contains(&3));
+        let around = vec![
+            message(1, "caption", Some(7)),
+            message(2, "before", None),
+            message(4, "", Some(7)),
+            message(5, "other album", Some(8)),
+        ];
+        let mut edited = message(3, "", Some(7));
+        edited.edit_date = Some(100);
+        let post = album_from(edited, around, 7);
+        // The album takes the id of its first part, under which it is mapped
+        assert_eq!((post.id(), post.text()), (1, "caption"));
+        assert_eq!(post.edit_date(), Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_albums_and_messages() {
+        let mut iter = FakeIter::new(vec![
+            (1, "album a", Some(10)),
+            (2, "single", None),
+            (3, "", Some(10)),
+            (4, "album b", Some(20)),
+            (5, "", Some(10)),
+            (6, "", Some(20)),
+            (7, "last", None),
+        ]);
+        let posts = scan(&mut iter, -1, 10, &filter()).await.unwrap();
+        assert_eq!(
+            summary(&posts),
+            vec![(1, "album a"), (2, "single"), (4, "album b"), (7, "last")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_album_already_mirrored_is_not_repeated() {
+        let mut iter = FakeIter::new(vec![
+            (1, "caption", Some(7)),
+            (2, "", Some(7)),
+            (3, "", Some(7)),
+            (4, "new", None),
+        ]);
+        let posts = scan(&mut iter, 1, 10, &filter()).await.unwrap();
+        assert_eq!(summary(&posts), vec![(4, "new")]);
+    }
+
+    #[tokio::test]
+    async fn test_keeps_oldest_posts_and_filters() {
+        let mut iter = FakeIter::new(vec![
+            (1, "a", None),
+            (2, "skip #tgonly", None),
+            (3, "c", None),
+            (4, "d", None),
+            (5, "", None),
+        ]);
+        let posts = scan(&mut iter, -1, 2, &filter()).await.unwrap();
+        assert_eq!(summary(&posts), vec![(1, "a"), (3, "c")]);
+    }
+
+    #[tokio::test]
+    async fn test_resumed_ids_of_every_destination() {
+        let dir = TempDir::new("resumed");
+        let storage = crate::storage::Storage::Files;
+        let mut progress = vec![];
+        for name in ["twitter", "mastodon"] {
+            let path = dir.join(name);
+            tokio::fs::create_dir_all(&path).await.unwrap();
+            let path = path.to_str().unwrap();
+            progress.push((
+                Journal::open(&storage, path).await,
+                DeadLetters::open(&storage, path, "chat").await,
+            ));
+        }
+        let post = |id| Post::new(id, String::new());
+        let (twitter, twitter_letters) = &progress[0];
+        let (mastodon, mastodon_letters) = &progress[1];
+        for id in [3, 5, 6, 12] {
+            twitter.record(&post(id), Step::Fetched, |_| {}).await;
+        }
+        twitter_letters.add(6, "poster", &"Tweet is too long").await;
+        twitter_letters.add(2, "poster", &"Timeout").await;
+        twitter_letters.requeue(Some(2)).await;
+        mastodon.record(&post(5), Step::Uploaded, |_| {}).await;
+        mastodon.record(&post(9), Step::Done, |_| {}).await;
+        // Given up on another destination only
+        mastodon_letters
+            .add(3, "poster", &"Blob is too large")
+            .await;
+
+        let resumed = resumed_ids(progress.iter().map(|(j, d)| (j, d)), 10).await;
+        assert_eq!(resumed, vec![2, 3, 5]);
+    }
+}
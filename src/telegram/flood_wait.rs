@@ -0,0 +1,115 @@
+use grammers_client::client::messages::InvocationError;
+use std::time::Duration;
+
+/// Number of times a request is sent again after a FLOOD_WAIT
+const MAX_FLOOD_RETRIES: u32 = 5;
+
+/// Waits out the FLOOD_WAIT errors Telegram returns when too many requests are sent, so the
+/// request can be sent again
+#[derive(Debug, Clone, Copy)]
+pub struct FloodWait {
+    max_wait: Duration,
+}
+
+impl FloodWait {
+    /// # Arguments
+    ///  * `max_wait` - The longest wait accepted, the request fails if Telegram asks for more
+    pub fn new(max_wait: Duration) -> Self {
+        FloodWait { max_wait }
+    }
+
+    /// Sleeps for the time Telegram asked for and returns `true` if the error is a FLOOD_WAIT
+    /// that may be waited out, otherwise returns `false` right away
+    ///
+    /// # Arguments
+    ///  * `attempt` - The number of the attempt that failed, starting at 1
+    ///  * `what` - The request, for logging
+    pub async fn wait(&self, attempt: u32, what: &str, error: &InvocationError) -> bool {
+        let wait = match flood_wait_of(error) {
+            Some(wait) => wait,
+            None => return false,
+        };
+        if attempt > MAX_FLOOD_RETRIES || wait > self.max_wait {
+            log::error!(
+                "{} got FLOOD_WAIT of {:?} on attempt {}, giving up",
+                what,
+                wait,
+                attempt
+            );
+            return false;
+        }
+        log::warn!("{} got FLOOD_WAIT, sleeping for {:?}", what, wait);
+        tokio::time::sleep(wait).await;
+        true
+    }
+
+    /// Same as [`FloodWait::wait`] for the I/O errors of media downloads, which wrap the
+    /// Telegram error
+    pub async fn wait_io(&self, attempt: u32, what: &str, error: &std::io::Error) -> bool {
+        match error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<InvocationError>())
+        {
+            Some(error) => self.wait(attempt, what, error).await,
+            None => false,
+        }
+    }
+}
+
+/// How long Telegram asks to wait before sending the request again, if it is a FLOOD_WAIT
+pub fn flood_wait_of(error: &InvocationError) -> Option<Duration> {
+    match error {
+        // The number of seconds in FLOOD_WAIT_X is parsed as the value of the error
+        InvocationError::Rpc(rpc)
+            if rpc.name.starts_with("FLOOD_") && rpc.name.ends_with("WAIT") =>
+        {
+            Some(Duration::from_secs(rpc.value.unwrap_or(1) as u64))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use grammers_client::client::messages::InvocationError::Rpc;
+    use grammers_client::RpcError;
+
+    fn rpc_error(code: i32, name: &str, value: Option<u32>) -> InvocationError {
+        Rpc(RpcError {
+            code,
+            name: name.to_string(),
+            value,
+            caused_by: None,
+        })
+    }
+
+    #[test]
+    fn test_flood_wait_of() {
+        assert_eq!(
+            flood_wait_of(&rpc_error(420, "FLOOD_WAIT", Some(31))),
+            Some(Duration::from_secs(31))
+        );
+        assert_eq!(
+            flood_wait_of(&rpc_error(420, "FLOOD_PREMIUM_WAIT", Some(5))),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            flood_wait_of(&rpc_error(400, "CHANNEL_INVALID", None)),
+            None
+        );
+        assert_eq!(flood_wait_of(&InvocationError::Dropped), None);
+    }
+
+    #[tokio::test]
+    async fn test_wait_is_capped() {
+        let flood_wait = FloodWait::new(Duration::from_secs(10));
+        let error = rpc_error(420, "FLOOD_WAIT", Some(0));
+        assert!(flood_wait.wait(1, "test", &error).await);
+        assert!(!flood_wait.wait(MAX_FLOOD_RETRIES + 1, "test", &error).await);
+        let long = rpc_error(420, "FLOOD_WAIT", Some(3600));
+        assert!(!flood_wait.wait(1, "test", &long).await);
+        let io = std::io::Error::other(error);
+        assert!(flood_wait.wait_io(1, "test", &io).await);
+    }
+}
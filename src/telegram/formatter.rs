@@ -0,0 +1,267 @@
+use crate::telegram::types::{Entity, EntityKind};
+use crate::twitter::text::{weighted_length, MAX_TWEET_LENGTH};
+use crate::types::{FormattingConfig, LinkStyle, Post, Processor, Runnable};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+
+const SPOILER: &str = "[spoiler]";
+
+/// Renders the formatting entities of the Telegram posts as text Twitter can show
+///
+/// Hidden links get their URL back, mentions of Telegram users become the Twitter handles
+/// configured for them and spoilers are hidden. Bold, italic and code may be written with Unicode
+/// styled letters, unless they would make a post that fits in a tweet overflow it, as they
+/// count twice.
+pub struct TelegramFormatter {
+    config: FormattingConfig,
+    receiver: Option<Receiver<Post>>,
+    sender: Option<Sender<Post>>,
+}
+
+impl TelegramFormatter {
+    pub fn new(config: &FormattingConfig) -> Self {
+        TelegramFormatter {
+            config: config.clone(),
+            receiver: None,
+            sender: None,
+        }
+    }
+
+    fn format(&self, post: &mut Post) {
+        if post.entities().is_empty() {
+            return;
+        }
+        let mut text = render(post.text(), post.entities(), &self.config);
+        if self.config.styles && weighted_length(&text) > MAX_TWEET_LENGTH {
+            let plain_config = FormattingConfig {
+                styles: false,
+                ..self.config.clone()
+            };
+            let plain = render(post.text(), post.entities(), &plain_config);
+            if weighted_length(&plain) <= MAX_TWEET_LENGTH {
+                text = plain;
+            }
+        }
+        post.set_text(text);
+    }
+}
+
+/// Renders the text with its entities, whose offsets and lengths are in UTF-16 code units
+fn render(text: &str, entities: &[Entity], config: &FormattingConfig) -> String {
+    let mut rendered = String::new();
+    let mut appended: Vec<&str> = vec![];
+    let mut position = 0;
+    for c in text.chars() {
+        let start = position;
+        position += c.len_utf16();
+        let active: Vec<&Entity> = entities
+            .iter()
+            .filter(|e| e.offset <= start && start < e.offset + e.length)
+            .collect();
+
+        // Entities whose whole text is replaced
+        let replaced = active.iter().find_map(|e| {
+            let replacement = match &e.kind {
+                EntityKind::Spoiler if !config.show_spoilers => Some(SPOILER.to_string()),
+                EntityKind::Mention => {
+                    let username = entity_text(text, e).trim_start_matches('@').to_lowercase();
+                    config.mentions.get(&username).map(|h| handle(h))
+                }
+                EntityKind::MentionName(user_id) => {
+                    config.mentions.get(&user_id.to_string()).map(|h| handle(h))
+                }
+                _ => None,
+            };
+            replacement.map(|r| (e.offset, r))
+        });
+        match replaced {
+            Some((offset, replacement)) => {
+                if offset == start {
+                    rendered.push_str(&replacement);
+                }
+            }
+            None if config.styles => {
+                let has = |kind: fn(&EntityKind) -> bool| active.iter().any(|e| kind(&e.kind));
+                let style = if has(|k| matches!(k, EntityKind::Code | EntityKind::Pre)) {
+                    Style::Monospace
+                } else {
+                    match (
+                        has(|k| matches!(k, EntityKind::Bold)),
+                        has(|k| matches!(k, EntityKind::Italic)),
+                    ) {
+                        (true, true) => Style::BoldItalic,
+                        (true, false) => Style::Bold,
+                        (false, true) => Style::Italic,
+                        (false, false) => Style::Plain,
+                    }
+                };
+                rendered.push(style.apply(c));
+            }
+            None => rendered.push(c),
+        }
+
+        // Links whose text ends here
+        for entity in entities.iter().filter(|e| e.offset + e.length == position) {
+            if let EntityKind::TextUrl(url) = &entity.kind {
+                if entity_text(text, entity).trim() == url {
+                    continue;
+                }
+                match config.links {
+                    LinkStyle::Inline => rendered.push_str(&format!(" ({})", url)),
+                    LinkStyle::Append => appended.push(url),
+                }
+            }
+        }
+    }
+
+    appended.dedup();
+    if !appended.is_empty() {
+        rendered.push_str("\n\n");
+        rendered.push_str(&appended.join("\n"));
+    }
+    rendered
+}
+
+fn handle(handle: &str) -> String {
+    format!("@{}", handle.trim_start_matches('@'))
+}
+
+/// The part of the text the entity covers
+fn entity_text(text: &str, entity: &Entity) -> String {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let end = (entity.offset + entity.length).min(units.len());
+    String::from_utf16_lossy(&units[entity.offset.min(end)..end])
+}
+
+#[derive(Clone, Copy)]
+enum Style {
+    Plain,
+    Bold,
+    Italic,
+    BoldItalic,
+    Monospace,
+}
+
+impl Style {
+    /// The Mathematical Alphanumeric Symbols for ASCII letters and digits, other characters are
+    /// left as they are
+    fn apply(self, c: char) -> char {
+        let (upper, lower, digit) = match self {
+            Style::Plain => return c,
+            Style::Bold => (0x1D400, 0x1D41A, Some(0x1D7CE)),
+            Style::Italic => (0x1D434, 0x1D44E, None),
+            Style::BoldItalic => (0x1D468, 0x1D482, Some(0x1D7CE)),
+            Style::Monospace => (0x1D670, 0x1D68A, Some(0x1D7F6)),
+        };
+        let code = match c {
+            // The italic h was encoded before the others, as the Planck constant
+            'h' if matches!(self, Style::Italic) => return '\u{210E}',
+            'A'..='Z' => upper + (c as u32 - 'A' as u32),
+            'a'..='z' => lower + (c as u32 - 'a' as u32),
+            '0'..='9' => match digit {
+                Some(zero) => zero + (c as u32 - '0' as u32),
+                None => return c,
+            },
+            _ => return c,
+        };
+        char::from_u32(code).unwrap_or(c)
+    }
+}
+
+impl Processor<Post, Post> for TelegramFormatter {
+    fn set_input(&mut self, input: Receiver<Post>) {
+        self.receiver = Some(input);
+    }
+    fn set_output(&mut self, output: Sender<Post>) {
+        self.sender = Some(output);
+    }
+}
+
+impl Runnable for TelegramFormatter {
+    fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(mut post) = self.receiver.as_mut().unwrap().recv().await {
+                self.format(&mut post);
+                self.sender
+                    .as_ref()
+                    .unwrap()
+                    .send(post)
+                    .await
+                    .expect("send");
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entity(offset: usize, length: usize, kind: EntityKind) -> Entity {
+        Entity {
+            offset,
+            length,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_links() {
+        let text = "Read the docs and https://example.com";
+        let entities = vec![
+            entity(9, 4, EntityKind::TextUrl("https://docs.rs".to_string())),
+            entity(
+                18,
+                19,
+                EntityKind::TextUrl("https://example.com".to_string()),
+            ),
+        ];
+        let mut config = FormattingConfig::default();
+        assert_eq!(
+            render(text, &entities, &config),
+            "Read the docs (https://docs.rs) and https://example.com"
+        );
+
+        config.links = LinkStyle::Append;
+        assert_eq!(
+            render(text, &entities, &config),
+            "Read the docs and https://example.com\n\nhttps://docs.rs"
+        );
+    }
+
+    #[test]
+    fn test_mentions_and_spoilers() {
+        // The emoji counts as two UTF-16 code units
+        let text = "🎉 @tguser met Jane: the end";
+        let mut config = FormattingConfig::default();
+        config
+            .mentions
+            .insert("tguser".to_string(), "twuser".to_string());
+        config
+            .mentions
+            .insert("42".to_string(), "@jane".to_string());
+        let entities = vec![
+            entity(3, 7, EntityKind::Mention),
+            entity(15, 4, EntityKind::MentionName(42)),
+            entity(21, 7, EntityKind::Spoiler),
+        ];
+        assert_eq!(
+            render(text, &entities, &config),
+            "🎉 @twuser met @jane: [spoiler]"
+        );
+    }
+
+    #[test]
+    fn test_styles() {
+        let config = FormattingConfig {
+            styles: true,
+            ..Default::default()
+        };
+        let entities = vec![
+            entity(0, 4, EntityKind::Bold),
+            entity(2, 5, EntityKind::Italic),
+            entity(8, 2, EntityKind::Code),
+        ];
+        assert_eq!(render("Bold hi x1", &entities, &config), "𝐁𝐨𝒍𝒅 ℎ𝑖 𝚡𝟷");
+    }
+}
@@ -0,0 +1,184 @@
+use crate::mapping::MappingStore;
+use crate::telegram::types::{ChannelInfo, ForwardOrigin, TelegramClient};
+use crate::types::{ForwardPolicy, Post, Processor, Route, Runnable};
+use grammers_client::types::Chat;
+use std::collections::HashMap;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+
+/// Credits the chat forwarded posts come from, or quotes the tweet of the original post when
+/// that chat is mirrored too. Other posts go through untouched
+///
+/// Skipping forwarded posts is left to the filter of the generator.
+pub struct TelegramForwards<T: TelegramClient> {
+    client: T,
+    chat_name: String,
+    chat: Option<Chat>,
+    policy: ForwardPolicy,
+    /// The chat and the mapping of every route, to find the tweets of the original posts
+    mirrored: Vec<(String, MappingStore)>,
+    channels: HashMap<i64, Option<ChannelInfo>>,
+    receiver: Option<Receiver<Post>>,
+    sender: Option<Sender<Post>>,
+}
+
+impl<T: TelegramClient> TelegramForwards<T> {
+    pub fn new(client: T, route: &Route, mirrored: Vec<(String, MappingStore)>) -> Self {
+        TelegramForwards {
+            client,
+            chat_name: route.chat_name.clone(),
+            chat: None,
+            policy: route.forwards,
+            mirrored,
+            channels: HashMap::new(),
+            receiver: None,
+            sender: None,
+        }
+    }
+
+    /// Completes the origin with the title and username of the channel
+    async fn resolve(&mut self, post: &Post, mut origin: ForwardOrigin) -> ForwardOrigin {
+        let channel_id = match origin.channel_id {
+            Some(id) => id,
+            None => return origin,
+        };
+        if !self.channels.contains_key(&channel_id) {
+            if self.chat.is_none() {
+                self.chat = self
+                    .client
+                    .resolve_username(&self.chat_name)
+                    .await
+                    .ok()
+                    .flatten();
+            }
+            let channel = match &self.chat {
+                Some(chat) => self
+                    .client
+                    .forward_channel(chat, post.id(), channel_id)
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::warn!("[Forwards] Error fetching channel {}: {}", channel_id, e);
+                        None
+                    }),
+                None => None,
+            };
+            self.channels.insert(channel_id, channel);
+        }
+        if let Some(Some(channel)) = self.channels.get(&channel_id) {
+            origin.name = Some(channel.title.clone());
+            origin.username = channel.username.clone();
+        }
+        origin
+    }
+
+    /// The first tweet of the original post, if its chat is mirrored by a route
+    async fn original_tweet(&self, origin: &ForwardOrigin) -> Option<String> {
+        let username = origin.username.as_ref()?;
+        let message_id = origin.message_id?;
+        for (chat, mappings) in &self.mirrored {
+            if !chat.eq_ignore_ascii_case(username) {
+                continue;
+            }
+            let tweet = mappings
+                .get(message_id)
+                .await
+                .filter(|m| !m.deleted)
+                .and_then(|m| m.tweet_ids.first().cloned());
+            if tweet.is_some() {
+                return tweet;
+            }
+        }
+        None
+    }
+
+    async fn handle(&mut self, mut post: Post) -> Post {
+        let origin = match post.forward() {
+            Some(origin) if self.policy != ForwardPolicy::Keep => origin.clone(),
+            _ => return post,
+        };
+        let origin = self.resolve(&post, origin).await;
+        if self.policy == ForwardPolicy::Quote {
+            if let Some(tweet) = self.original_tweet(&origin).await {
+                log::info!("Telegram post {} quotes tweet {}", post.id(), tweet);
+                post.set_quote(Some(tweet));
+                post.set_forward(Some(origin));
+                return post;
+            }
+        }
+        if let Some(line) = attribution(&origin) {
+            post.set_text(if post.text().is_empty() {
+                line
+            } else {
+                format!("{}\n\n{}", post.text(), line)
+            });
+        }
+        post.set_forward(Some(origin));
+        post
+    }
+}
+
+/// The line crediting the original chat, e.g. `"via Some Channel https://t.me/channel/12"`.
+/// The Telegram username is not written as a mention, it may belong to someone else on Twitter
+fn attribution(origin: &ForwardOrigin) -> Option<String> {
+    let name = origin.name.clone().or_else(|| origin.username.clone())?;
+    let author = origin
+        .author
+        .as_ref()
+        .map(|author| format!(" ({})", author))
+        .unwrap_or_default();
+    Some(match origin.link() {
+        Some(link) => format!("via {}{} {}", name, author, link),
+        None => format!("via {}{}", name, author),
+    })
+}
+
+impl<T: TelegramClient> Processor<Post, Post> for TelegramForwards<T> {
+    fn set_input(&mut self, input: Receiver<Post>) {
+        self.receiver = Some(input);
+    }
+    fn set_output(&mut self, output: Sender<Post>) {
+        self.sender = Some(output);
+    }
+}
+
+impl<T: TelegramClient> Runnable for TelegramForwards<T> {
+    fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(post) = self.receiver.as_mut().unwrap().recv().await {
+                let post = self.handle(post).await;
+                self.sender
+                    .as_ref()
+                    .unwrap()
+                    .send(post)
+                    .await
+                    .expect("send");
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attribution() {
+        let mut origin = ForwardOrigin {
+            channel_id: Some(1),
+            message_id: Some(12),
+            name: Some("Some Channel".to_string()),
+            username: Some("somechannel".to_string()),
+            author: None,
+        };
+        assert_eq!(
+            attribution(&origin).unwrap(),
+            "via Some Channel https://t.me/somechannel/12"
+        );
+
+        origin.username = None;
+        origin.author = Some("Jane".to_string());
+        assert_eq!(attribution(&origin).unwrap(), "via Some Channel (Jane)");
+
+        assert_eq!(attribution(&ForwardOrigin::default()), None);
+    }
+}
@@ -1,5 +1,10 @@
+pub(crate) mod deletions;
+pub(crate) mod dispatcher;
 pub(crate) mod downloader;
 pub(crate) mod fetcher;
+pub(crate) mod flood_wait;
+pub(crate) mod formatter;
+pub(crate) mod forwards;
 pub(crate) mod telegram_client;
 pub(crate) mod types;
 
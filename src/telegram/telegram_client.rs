@@ -1,31 +1,53 @@
-use crate::telegram::types::{TelegramClient, TelegramMessage, TelegramMessageIter};
+use crate::telegram::flood_wait::FloodWait;
+use crate::telegram::types::{
+    ChannelInfo, Entity, EntityKind, ForwardOrigin, TelegramClient, TelegramMessage,
+    TelegramMessageIter, TelegramUpdate,
+};
 use crate::{telegram, Cfg};
 use async_trait::async_trait;
 use grammers_client::client::messages::{InvocationError, MessageIter};
 use grammers_client::types::{Chat, Media, Message};
-use grammers_client::Client;
+use grammers_client::{Client, Update};
 use grammers_session::PackedChat;
+use grammers_tl_types as tl;
 use std::path::Path;
+use std::time::Duration;
 
 pub struct GrammersIter {
     iter: MessageIter,
+    flood_wait: FloodWait,
 }
 impl GrammersIter {
-    fn new(iter: MessageIter) -> Self {
-        GrammersIter { iter }
+    fn new(iter: MessageIter, flood_wait: FloodWait) -> Self {
+        GrammersIter { iter, flood_wait }
     }
 }
 #[async_trait]
 impl TelegramMessageIter<GrammersMessage> for GrammersIter {
     async fn total(&mut self) -> Result<usize, InvocationError> {
-        self.iter.total().await
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.iter.total().await {
+                Err(e) if self.flood_wait.wait(attempt, "Counting messages", &e).await => continue,
+                result => return result,
+            }
+        }
     }
 
     async fn next(&mut self) -> Result<Option<GrammersMessage>, InvocationError> {
-        self.iter.next().await.map(|m| m.map(GrammersMessage::new))
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.iter.next().await {
+                Err(e) if self.flood_wait.wait(attempt, "Fetching messages", &e).await => continue,
+                result => return result.map(|m| m.map(GrammersMessage::new)),
+            }
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct GrammersMessage {
     msg: Message,
 }
@@ -39,6 +61,10 @@ impl TelegramMessage for GrammersMessage {
         self.msg.id()
     }
 
+    fn chat_id(&self) -> i64 {
+        self.msg.chat().id()
+    }
+
     fn text(&self) -> &str {
         self.msg.text()
     }
@@ -50,17 +76,78 @@ impl TelegramMessage for GrammersMessage {
     fn media(&self) -> Option<Media> {
         self.msg.media()
     }
+
+    fn reply_to_message_id(&self) -> Option<i32> {
+        self.msg.reply_to_message_id()
+    }
+
+    fn date(&self) -> i64 {
+        self.msg.date().timestamp()
+    }
+
+    fn post_author(&self) -> Option<&str> {
+        self.msg.post_author()
+    }
+
+    fn forward(&self) -> Option<ForwardOrigin> {
+        let tl::enums::MessageFwdHeader::Header(header) = self.msg.forward_header()?;
+        Some(ForwardOrigin {
+            channel_id: match header.from_id {
+                Some(tl::enums::Peer::Channel(channel)) => Some(channel.channel_id),
+                _ => None,
+            },
+            message_id: header.channel_post,
+            name: header.from_name,
+            username: None,
+            author: header.post_author,
+        })
+    }
+
+    fn entities(&self) -> Vec<Entity> {
+        use tl::enums::MessageEntity as E;
+        let entities = match self.msg.fmt_entities() {
+            Some(entities) => entities,
+            None => return vec![],
+        };
+        entities
+            .iter()
+            .filter_map(|entity| {
+                let (offset, length, kind) = match entity {
+                    E::Bold(e) => (e.offset, e.length, EntityKind::Bold),
+                    E::Italic(e) => (e.offset, e.length, EntityKind::Italic),
+                    E::Code(e) => (e.offset, e.length, EntityKind::Code),
+                    E::Pre(e) => (e.offset, e.length, EntityKind::Pre),
+                    E::Spoiler(e) => (e.offset, e.length, EntityKind::Spoiler),
+                    E::TextUrl(e) => (e.offset, e.length, EntityKind::TextUrl(e.url.clone())),
+                    E::Mention(e) => (e.offset, e.length, EntityKind::Mention),
+                    E::MentionName(e) => (e.offset, e.length, EntityKind::MentionName(e.user_id)),
+                    _ => return None,
+                };
+                Some(Entity {
+                    offset: offset as usize,
+                    length: length as usize,
+                    kind,
+                })
+            })
+            .collect()
+    }
+
+    fn edit_date(&self) -> Option<i64> {
+        self.msg.edit_date().map(|d| d.timestamp())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct GrammersClient {
     client: Client,
+    flood_wait: FloodWait,
 }
 
 impl GrammersClient {
     pub async fn new(config: &Cfg) -> Self {
         GrammersClient {
             client: telegram::create_client(config).await.ok().unwrap(),
+            flood_wait: FloodWait::new(Duration::from_secs(config.telegram.max_flood_wait)),
         }
     }
 }
@@ -71,12 +158,40 @@ impl TelegramClient for GrammersClient {
     type I = GrammersIter;
 
     async fn resolve_username(&self, username: &str) -> Result<Option<Chat>, InvocationError> {
-        self.client.resolve_username(username).await
+        let what = format!("Resolving {}", username);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.client.resolve_username(username).await {
+                Err(e) if self.flood_wait.wait(attempt, &what, &e).await => continue,
+                result => return result,
+            }
+        }
     }
 
     fn iter_messages<C: Into<PackedChat>>(&self, chat: C) -> GrammersIter {
         let buffer = self.client.iter_messages(chat);
-        GrammersIter::new(buffer)
+        GrammersIter::new(buffer, self.flood_wait)
+    }
+
+    async fn get_messages_by_id<C: Into<PackedChat> + Send>(
+        &self,
+        chat: C,
+        ids: &[i32],
+    ) -> Result<Vec<Option<GrammersMessage>>, InvocationError> {
+        let chat = chat.into();
+        let mut attempt = 0;
+        let messages = loop {
+            attempt += 1;
+            match self.client.get_messages_by_id(chat, ids).await {
+                Err(e) if self.flood_wait.wait(attempt, "Fetching messages", &e).await => continue,
+                result => break result?,
+            }
+        };
+        Ok(messages
+            .into_iter()
+            .map(|m| m.map(GrammersMessage::new))
+            .collect())
     }
 
     async fn download_media<P: AsRef<Path> + Send>(
@@ -84,6 +199,73 @@ impl TelegramClient for GrammersClient {
         media: &Media,
         path: P,
     ) -> Result<(), std::io::Error> {
-        self.client.download_media(media, path).await
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.client.download_media(media, path.as_ref()).await {
+                Err(e)
+                    if self
+                        .flood_wait
+                        .wait_io(attempt, "Downloading media", &e)
+                        .await =>
+                {
+                    continue
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn forward_channel(
+        &self,
+        chat: &Chat,
+        msg_id: i32,
+        channel_id: i64,
+    ) -> Result<Option<ChannelInfo>, InvocationError> {
+        // The channel is only known through the message, it may not be accessible otherwise
+        let request = tl::functions::channels::GetChannels {
+            id: vec![tl::types::InputChannelFromMessage {
+                peer: chat.pack().to_input_peer(),
+                msg_id,
+                channel_id,
+            }
+            .into()],
+        };
+        let mut attempt = 0;
+        let chats = loop {
+            attempt += 1;
+            match self.client.invoke(&request).await {
+                Err(e) if self.flood_wait.wait(attempt, "Fetching channel", &e).await => continue,
+                result => break result?,
+            }
+        };
+        let chats = match chats {
+            tl::enums::messages::Chats::Chats(chats) => chats.chats,
+            tl::enums::messages::Chats::Slice(chats) => chats.chats,
+        };
+        Ok(chats.into_iter().find_map(|chat| match chat {
+            tl::enums::Chat::Channel(channel) => Some(ChannelInfo {
+                title: channel.title,
+                username: channel.username,
+            }),
+            _ => None,
+        }))
+    }
+
+    async fn next_update(
+        &self,
+    ) -> Result<Option<TelegramUpdate<GrammersMessage>>, InvocationError> {
+        let update = match self.client.next_update().await? {
+            Some(update) => update,
+            None => return Ok(None),
+        };
+        Ok(Some(match update {
+            Update::NewMessage(msg) => TelegramUpdate::NewMessage(GrammersMessage::new(msg)),
+            Update::MessageEdited(msg) => TelegramUpdate::MessageEdited(GrammersMessage::new(msg)),
+            Update::MessageDeleted(deletion) => {
+                TelegramUpdate::MessageDeleted(deletion.channel_id(), deletion.messages().to_vec())
+            }
+            _ => TelegramUpdate::Other,
+        }))
     }
 }
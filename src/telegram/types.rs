@@ -6,17 +6,100 @@ use grammers_client::client::auth::InvocationError;
 use grammers_client::types::{Chat, Media};
 use grammers_session::PackedChat;
 
+/// Where a forwarded message was first published
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ForwardOrigin {
+    /// The channel, unless the message comes from a user
+    pub channel_id: Option<i64>,
+    /// The id of the message in the channel
+    pub message_id: Option<i32>,
+    /// The title of the channel, or the name of a user hiding their account
+    pub name: Option<String>,
+    pub username: Option<String>,
+    /// The signature of the author of the channel post
+    pub author: Option<String>,
+}
+
+impl ForwardOrigin {
+    /// The public link of the original message, for channels with a username
+    pub fn link(&self) -> Option<String> {
+        let username = self.username.as_ref()?;
+        match self.message_id {
+            Some(id) => Some(format!("https://t.me/{}/{}", username, id)),
+            None => Some(format!("https://t.me/{}", username)),
+        }
+    }
+}
+
+/// A formatting entity of a message, the offset and length are in UTF-16 code units
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entity {
+    pub offset: usize,
+    pub length: usize,
+    pub kind: EntityKind,
+}
+
+/// The entities that change how a message is rendered on Twitter
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntityKind {
+    Bold,
+    Italic,
+    Code,
+    Pre,
+    Spoiler,
+    /// A text hiding the URL it links to
+    TextUrl(String),
+    /// An `@username`
+    Mention,
+    /// A user without username, by id
+    MentionName(i64),
+}
+
+/// The public details of a channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelInfo {
+    pub title: String,
+    pub username: Option<String>,
+}
+
+/// Maximum number of messages Telegram returns per request
+pub const MAX_MESSAGES_PER_REQUEST: usize = 100;
+
 #[async_trait]
 pub trait TelegramClient: Sync + Send + 'static {
     type M: TelegramMessage;
     type I: TelegramMessageIter<Self::M>;
     async fn resolve_username(&self, username: &str) -> Result<Option<Chat>, InvocationError>;
     fn iter_messages<C: Into<PackedChat>>(&self, chat: C) -> Self::I;
+    async fn get_messages_by_id<C: Into<PackedChat> + Send>(
+        &self,
+        chat: C,
+        ids: &[i32],
+    ) -> Result<Vec<Option<Self::M>>, InvocationError>;
     async fn download_media<P: AsRef<Path> + Send>(
         &self,
         media: &Media,
         path: P,
     ) -> Result<(), Error>;
+    /// The channel a message of the chat was forwarded from
+    async fn forward_channel(
+        &self,
+        chat: &Chat,
+        msg_id: i32,
+        channel_id: i64,
+    ) -> Result<Option<ChannelInfo>, InvocationError>;
+    /// Waits for the next update, returns `None` when the client is disconnected
+    async fn next_update(&self) -> Result<Option<TelegramUpdate<Self::M>>, InvocationError>;
+}
+
+/// The updates relevant for mirroring, received while listening to Telegram
+#[derive(Clone)]
+pub enum TelegramUpdate<M: TelegramMessage> {
+    NewMessage(M),
+    MessageEdited(M),
+    /// The id of the channel, if known, and the ids of the messages deleted
+    MessageDeleted(Option<i64>, Vec<i32>),
+    Other,
 }
 
 #[async_trait]
@@ -25,9 +108,20 @@ pub trait TelegramMessageIter<M: TelegramMessage>: Send + Sync {
     async fn next(&mut self) -> Result<Option<M>, InvocationError>;
 }
 
-pub trait TelegramMessage: Send {
+pub trait TelegramMessage: Clone + Send {
     fn id(&self) -> i32;
+    fn chat_id(&self) -> i64;
     fn text(&self) -> &str;
     fn grouped_id(&self) -> Option<i64>;
     fn media(&self) -> Option<Media>;
+    fn reply_to_message_id(&self) -> Option<i32>;
+    /// Unix timestamp of the publication
+    fn date(&self) -> i64;
+    /// Signature of the author, for channels that sign their posts
+    fn post_author(&self) -> Option<&str>;
+    /// Where the message was first published, if it was forwarded
+    fn forward(&self) -> Option<ForwardOrigin>;
+    fn entities(&self) -> Vec<Entity>;
+    /// Unix timestamp of the last edit, if the message was edited
+    fn edit_date(&self) -> Option<i64>;
 }
@@ -0,0 +1,363 @@
+use crate::types::{Post, Processor, Route, Runnable};
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::LazyLock;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+
+/// Rewrites the text of a post on its way to Twitter
+pub trait TextTransform: Send + Sync {
+    fn apply(&self, text: &str, post: &Post) -> String;
+}
+
+/// A transform as declared in a `[[transforms]]` table of `config.toml`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformConfig {
+    /// Replaces every match of the regex, `replacement` may refer to groups as `$1` or `${name}`
+    Replace {
+        pattern: String,
+        #[serde(default)]
+        replacement: String,
+    },
+    /// Removes the hashtags listed, or all of them when none is
+    StripHashtags {
+        #[serde(default)]
+        hashtags: Vec<String>,
+    },
+    /// Adds the hashtags the text doesn't have yet at the end
+    AppendHashtags { hashtags: Vec<String> },
+    /// Puts the template before the text
+    Prefix { template: String },
+    /// Puts the template after the text
+    Suffix { template: String },
+    /// Removes the last line matching the regex and everything after it
+    RemoveFooter { pattern: String },
+    /// Turns runs of spaces into a single one and of blank lines into a single blank line
+    CollapseWhitespace,
+}
+
+impl TransformConfig {
+    pub fn build(&self, route: &Route) -> Box<dyn TextTransform> {
+        match self {
+            TransformConfig::Replace {
+                pattern,
+                replacement,
+            } => Box::new(Replace {
+                regex: compile(pattern),
+                replacement: replacement.clone(),
+            }),
+            TransformConfig::StripHashtags { hashtags } => Box::new(StripHashtags {
+                hashtags: hashtags.iter().map(|h| normalize_hashtag(h)).collect(),
+            }),
+            TransformConfig::AppendHashtags { hashtags } => Box::new(AppendHashtags {
+                hashtags: hashtags.iter().map(|h| normalize_hashtag(h)).collect(),
+            }),
+            TransformConfig::Prefix { template } => Box::new(Template {
+                template: Template::render_chat(template, route),
+                before: true,
+            }),
+            TransformConfig::Suffix { template } => Box::new(Template {
+                template: Template::render_chat(template, route),
+                before: false,
+            }),
+            TransformConfig::RemoveFooter { pattern } => Box::new(RemoveFooter {
+                regex: compile(pattern),
+            }),
+            TransformConfig::CollapseWhitespace => Box::new(CollapseWhitespace),
+        }
+    }
+}
+
+fn compile(pattern: &str) -> Regex {
+    Regex::new(pattern).unwrap_or_else(|e| panic!("Invalid transform pattern {}: {}", pattern, e))
+}
+
+/// The hashtag with its `#` and lowercase, hashtags are case insensitive
+fn normalize_hashtag(hashtag: &str) -> String {
+    format!("#{}", hashtag.trim_start_matches('#').to_lowercase())
+}
+
+static HASHTAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|[\s(])(#\w+)").expect("Hashtag regex"));
+
+/// The hashtags of the text: a `#` at the start of a word, so not the fragment of a URL, followed
+/// by word characters
+fn hashtags(text: &str) -> Vec<regex::Match<'_>> {
+    HASHTAG
+        .captures_iter(text)
+        .filter_map(|caps| caps.get(1))
+        .collect()
+}
+
+struct Replace {
+    regex: Regex,
+    replacement: String,
+}
+
+impl TextTransform for Replace {
+    fn apply(&self, text: &str, _: &Post) -> String {
+        self.regex
+            .replace_all(text, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+struct StripHashtags {
+    hashtags: Vec<String>,
+}
+
+impl TextTransform for StripHashtags {
+    fn apply(&self, text: &str, _: &Post) -> String {
+        let mut stripped = String::new();
+        let mut last = 0;
+        for hashtag in hashtags(text) {
+            let name = hashtag.as_str().to_lowercase();
+            if !self.hashtags.is_empty() && !self.hashtags.contains(&name) {
+                continue;
+            }
+            // The space that separated it from the previous word, or else from the next one
+            let (mut start, mut end) = (hashtag.start(), hashtag.end());
+            if text[last..start].ends_with(' ') {
+                start -= 1;
+            } else if text[end..].starts_with(' ') {
+                end += 1;
+            }
+            stripped.push_str(&text[last..start]);
+            last = end;
+        }
+        if last == 0 {
+            return text.to_string();
+        }
+        stripped.push_str(&text[last..]);
+        // Hashtags at the end usually are on lines of their own
+        stripped.trim_end().to_string()
+    }
+}
+
+struct AppendHashtags {
+    hashtags: Vec<String>,
+}
+
+impl TextTransform for AppendHashtags {
+    fn apply(&self, text: &str, _: &Post) -> String {
+        let present: Vec<String> = hashtags(text)
+            .iter()
+            .map(|m| m.as_str().to_lowercase())
+            .collect();
+        let missing: Vec<&str> = self
+            .hashtags
+            .iter()
+            .filter(|h| !present.contains(h))
+            .map(|h| h.as_str())
+            .collect();
+        match (text.is_empty(), missing.is_empty()) {
+            (_, true) => text.to_string(),
+            (true, false) => missing.join(" "),
+            (false, false) => format!("{}\n\n{}", text, missing.join(" ")),
+        }
+    }
+}
+
+/// A prefix or a suffix, `{chat}` is replaced with the Telegram chat and `{id}` with the id of
+/// the Telegram message, e.g. `"\n\nt.me/{chat}/{id}"`
+struct Template {
+    template: String,
+    before: bool,
+}
+
+impl Template {
+    fn render_chat(template: &str, route: &Route) -> String {
+        template.replace("{chat}", &route.chat_name)
+    }
+}
+
+impl TextTransform for Template {
+    fn apply(&self, text: &str, post: &Post) -> String {
+        let rendered = self.template.replace("{id}", &post.id().to_string());
+        if self.before {
+            rendered + text
+        } else {
+            text.to_string() + &rendered
+        }
+    }
+}
+
+struct RemoveFooter {
+    regex: Regex,
+}
+
+impl TextTransform for RemoveFooter {
+    fn apply(&self, text: &str, _: &Post) -> String {
+        let lines: Vec<&str> = text.lines().collect();
+        match lines.iter().rposition(|line| self.regex.is_match(line)) {
+            Some(footer) => lines[..footer].join("\n").trim_end().to_string(),
+            None => text.to_string(),
+        }
+    }
+}
+
+struct CollapseWhitespace;
+
+impl TextTransform for CollapseWhitespace {
+    fn apply(&self, text: &str, _: &Post) -> String {
+        let mut collapsed: Vec<String> = vec![];
+        for line in text.lines() {
+            let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+            let blank = line.is_empty();
+            if !(blank && collapsed.last().map(|l| l.is_empty()).unwrap_or(true)) {
+                collapsed.push(line);
+            }
+        }
+        collapsed.join("\n").trim_end().to_string()
+    }
+}
+
+/// Applies the transforms of the route, in order, to the text of every post
+pub struct TextTransformer {
+    transforms: Vec<Box<dyn TextTransform>>,
+    receiver: Option<Receiver<Post>>,
+    sender: Option<Sender<Post>>,
+}
+
+impl TextTransformer {
+    pub fn new(route: &Route) -> Self {
+        TextTransformer {
+            transforms: route.transforms.iter().map(|t| t.build(route)).collect(),
+            receiver: None,
+            sender: None,
+        }
+    }
+
+    fn transform(&self, post: &mut Post) {
+        let text = self
+            .transforms
+            .iter()
+            .fold(post.text().to_string(), |text, transform| {
+                transform.apply(&text, post)
+            });
+        post.set_text(text);
+    }
+}
+
+impl Processor<Post, Post> for TextTransformer {
+    fn set_input(&mut self, input: Receiver<Post>) {
+        self.receiver = Some(input);
+    }
+    fn set_output(&mut self, output: Sender<Post>) {
+        self.sender = Some(output);
+    }
+}
+
+impl Runnable for TextTransformer {
+    fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(mut post) = self.receiver.as_mut().unwrap().recv().await {
+                self.transform(&mut post);
+                self.sender
+                    .as_ref()
+                    .unwrap()
+                    .send(post)
+                    .await
+                    .expect("send");
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(config: &str, text: &str) -> String {
+        #[derive(Deserialize)]
+        struct Transforms {
+            transforms: Vec<TransformConfig>,
+        }
+        let transforms: Transforms = toml::from_str(config).unwrap();
+        let route: Route = toml::from_str(
+            r#"
+            name = "news"
+            chat_name = "newschat"
+            [twitter]
+            api_key = ""
+            api_secret = ""
+            access_token = ""
+            access_token_secret = ""
+            "#,
+        )
+        .unwrap();
+        let post = Post::new(42, text.to_string());
+        transforms
+            .transforms
+            .iter()
+            .map(|t| t.build(&route))
+            .fold(text.to_string(), |text, t| t.apply(&text, &post))
+    }
+
+    #[test]
+    fn test_replace_and_templates() {
+        let config = r#"
+            [[transforms]]
+            type = "replace"
+            pattern = "(\\d+)%"
+            replacement = "$1 percent"
+            [[transforms]]
+            type = "prefix"
+            template = "News: "
+            [[transforms]]
+            type = "suffix"
+            template = "\nt.me/{chat}/{id}"
+        "#;
+        assert_eq!(
+            apply(config, "Up 5% today"),
+            "News: Up 5 percent today\nt.me/newschat/42"
+        );
+    }
+
+    #[test]
+    fn test_hashtags() {
+        let strip_all = "[[transforms]]\ntype = \"strip_hashtags\"";
+        assert_eq!(apply(strip_all, "#Breaking Big #news here"), "Big here");
+
+        let strip = "[[transforms]]\ntype = \"strip_hashtags\"\nhashtags = [\"tg\"]";
+        assert_eq!(apply(strip, "Hello #TG #world"), "Hello #world");
+        // Only the space next to the hashtag goes, and URL fragments are not hashtags
+        assert_eq!(
+            apply(
+                strip_all,
+                "Read  this:\n\thttps://example.com/page#tg (#tg)\n\n#tg #news"
+            ),
+            "Read  this:\n\thttps://example.com/page#tg ()"
+        );
+        assert_eq!(apply(strip, "C# and F#tg stay"), "C# and F#tg stay");
+
+        let append =
+            "[[transforms]]\ntype = \"append_hashtags\"\nhashtags = [\"news\", \"#World\"]";
+        assert_eq!(apply(append, "Hello #world"), "Hello #world\n\n#news");
+        assert_eq!(apply(append, "#News #world"), "#News #world");
+        assert_eq!(
+            apply(append, "See example.com/#news #world"),
+            "See example.com/#news #world\n\n#news"
+        );
+    }
+
+    #[test]
+    fn test_footer_and_whitespace() {
+        let config = r#"
+            [[transforms]]
+            type = "remove_footer"
+            pattern = "^Subscribe"
+            [[transforms]]
+            type = "collapse_whitespace"
+        "#;
+        assert_eq!(
+            apply(
+                config,
+                "Title  here\n\n\n\nBody\t text  \n\nSubscribe to @chat\nlinks"
+            ),
+            "Title here\n\nBody text"
+        );
+        assert_eq!(apply(config, "No footer"), "No footer");
+    }
+}
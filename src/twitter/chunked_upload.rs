@@ -0,0 +1,461 @@
+use crate::twitter::oauth::OAuth;
+use crate::twitter::rate_limit::{Endpoint, RateLimiter};
+use crate::twitter::types::is_retryable;
+use crate::types::TwitterConfig;
+use mime_guess::{mime, Mime};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, Method, RequestBuilder, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
+
+const UPLOAD_URL: &str = "https://upload.twitter.com/1.1/media/upload.json";
+
+/// Size of the segments appended, Twitter accepts up to 5 MB
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How many times the processing of an uploaded media is checked
+const PROCESSING_POLL_ATTEMPTS: u32 = 120;
+
+/// An upload is started over rather than resumed this close to its expiration, in seconds
+const EXPIRATION_MARGIN: u64 = 60;
+
+#[derive(Deserialize)]
+struct UploadResponse {
+    media_id_string: String,
+    expires_after_secs: Option<u64>,
+    processing_info: Option<ProcessingInfo>,
+}
+
+#[derive(Deserialize)]
+struct ProcessingInfo {
+    /// `pending`, `in_progress`, `succeeded` or `failed`
+    state: String,
+    check_after_secs: Option<u64>,
+    error: Option<ProcessingError>,
+}
+
+#[derive(Deserialize)]
+struct ProcessingError {
+    message: Option<String>,
+    name: Option<String>,
+}
+
+/// An upload in progress, kept so a retry goes on from the last segment appended
+#[derive(Clone, Debug)]
+struct Session {
+    media_id: String,
+    /// Unix timestamp after which Twitter drops the segments appended
+    expires: u64,
+    next_segment: u64,
+    finalized: bool,
+}
+
+/// The `media_category` of a media type, `None` if Twitter does not accept it
+pub fn media_category(media_type: &Mime) -> Option<&'static str> {
+    match (media_type.type_(), media_type.subtype()) {
+        (mime::IMAGE, mime::GIF) => Some("tweet_gif"),
+        (mime::IMAGE, _) => Some("tweet_image"),
+        (mime::VIDEO, _) => Some("tweet_video"),
+        _ => None,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time")
+        .as_secs()
+}
+
+/// Uploads media to Twitter in segments, with the INIT, APPEND, FINALIZE and STATUS commands
+///
+/// Large videos are uploaded in several requests, then processed asynchronously by Twitter
+/// before they can be attached. An upload that fails midway is resumed by the next attempt from
+/// the segment that failed, as long as Twitter keeps it. Clones share the uploads in progress.
+#[derive(Clone)]
+pub struct ChunkedUpload {
+    http: Client,
+    oauth: OAuth,
+    url: String,
+    chunk_size: u64,
+    limits: RateLimiter,
+    sessions: Arc<Mutex<HashMap<(PathBuf, String), Session>>>,
+}
+
+impl ChunkedUpload {
+    pub fn new(config: &TwitterConfig, limits: RateLimiter) -> Self {
+        ChunkedUpload {
+            http: Client::new(),
+            oauth: OAuth::new(config),
+            url: UPLOAD_URL.to_string(),
+            chunk_size: CHUNK_SIZE,
+            limits,
+            sessions: Default::default(),
+        }
+    }
+
+    /// A signed request to the upload endpoint, `params` are sent in the query
+    fn request(&self, method: Method, params: &[(&str, String)]) -> RequestBuilder {
+        let authorization = self.oauth.authorization(method.as_str(), &self.url, params);
+        self.http
+            .request(method, &self.url)
+            .query(params)
+            .header("Authorization", authorization)
+    }
+
+    /// Sends a request within the rate limit of the media endpoint, failing with the body of
+    /// the response if it was rejected
+    async fn send<F: Fn() -> RequestBuilder>(
+        &self,
+        request: F,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let response = self.limits.send(Endpoint::Media, request).await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        Err(format!("Twitter media upload failed with {}: {}", status, body).into())
+    }
+
+    /// Uploads a media, resuming the upload of the same file if a previous attempt failed
+    pub async fn upload(
+        &self,
+        file: &Path,
+        media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let category = media_category(media_type)
+            .ok_or_else(|| format!("Twitter media type unrecognized: {}", media_type))?;
+        let size = tokio::fs::metadata(file).await?.len();
+        let key = (file.to_path_buf(), media_type.to_string());
+
+        let resumed = self
+            .sessions
+            .lock()
+            .await
+            .get(&key)
+            .filter(|session| session.expires > now() + EXPIRATION_MARGIN)
+            .cloned();
+        let mut session = match resumed {
+            Some(session) => {
+                log::info!(
+                    "Resuming the upload of {:?} at segment {}",
+                    file,
+                    session.next_segment
+                );
+                session
+            }
+            None => self.init(size, media_type, category).await?,
+        };
+        self.sessions
+            .lock()
+            .await
+            .insert(key.clone(), session.clone());
+
+        let segments = size.div_ceil(self.chunk_size);
+        while session.next_segment < segments {
+            self.append(file, &session).await?;
+            session.next_segment += 1;
+            self.sessions
+                .lock()
+                .await
+                .insert(key.clone(), session.clone());
+        }
+
+        let processed = if session.finalized {
+            self.status(&session.media_id).await
+        } else {
+            let finalized = self.command("FINALIZE", &session.media_id).await;
+            if finalized.is_ok() {
+                session.finalized = true;
+                self.sessions
+                    .lock()
+                    .await
+                    .insert(key.clone(), session.clone());
+            }
+            finalized
+        };
+        match self.wait_processing(&session.media_id, processed?).await {
+            // Checked again by the next attempt
+            Err(e) if is_retryable(&*e) => Err(e),
+            result => {
+                self.sessions.lock().await.remove(&key);
+                result?;
+                Ok(session.media_id.parse()?)
+            }
+        }
+    }
+
+    async fn init(
+        &self,
+        size: u64,
+        media_type: &Mime,
+        category: &str,
+    ) -> Result<Session, Box<dyn Error + Send + Sync>> {
+        let params = [
+            ("command", "INIT".to_string()),
+            ("total_bytes", size.to_string()),
+            ("media_type", media_type.to_string()),
+            ("media_category", category.to_string()),
+        ];
+        let init: UploadResponse = self
+            .send(|| self.request(Method::POST, &params))
+            .await?
+            .json()
+            .await?;
+        Ok(Session {
+            media_id: init.media_id_string,
+            expires: now() + init.expires_after_secs.unwrap_or(u64::MAX / 2),
+            next_segment: 0,
+            finalized: false,
+        })
+    }
+
+    async fn append(
+        &self,
+        file: &Path,
+        session: &Session,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut content = tokio::fs::File::open(file).await?;
+        content
+            .seek(SeekFrom::Start(session.next_segment * self.chunk_size))
+            .await?;
+        let mut chunk = vec![];
+        content
+            .take(self.chunk_size)
+            .read_to_end(&mut chunk)
+            .await?;
+
+        let params = [
+            ("command", "APPEND".to_string()),
+            ("media_id", session.media_id.clone()),
+            ("segment_index", session.next_segment.to_string()),
+        ];
+        self.send(|| {
+            self.request(Method::POST, &params)
+                .multipart(Form::new().part("media", Part::bytes(chunk.clone())))
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Sends the FINALIZE or the STATUS command of an upload
+    async fn command(
+        &self,
+        command: &str,
+        media_id: &str,
+    ) -> Result<Option<ProcessingInfo>, Box<dyn Error + Send + Sync>> {
+        let method = if command == "STATUS" {
+            Method::GET
+        } else {
+            Method::POST
+        };
+        let params = [
+            ("command", command.to_string()),
+            ("media_id", media_id.to_string()),
+        ];
+        let response: UploadResponse = self
+            .send(|| self.request(method.clone(), &params))
+            .await?
+            .json()
+            .await?;
+        Ok(response.processing_info)
+    }
+
+    async fn status(
+        &self,
+        media_id: &str,
+    ) -> Result<Option<ProcessingInfo>, Box<dyn Error + Send + Sync>> {
+        self.command("STATUS", media_id).await
+    }
+
+    /// Waits until Twitter is done processing the media, checking as often as it asks
+    async fn wait_processing(
+        &self,
+        media_id: &str,
+        mut info: Option<ProcessingInfo>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for _ in 0..PROCESSING_POLL_ATTEMPTS {
+            let Some(processing) = info else {
+                return Ok(());
+            };
+            match processing.state.as_str() {
+                "succeeded" => return Ok(()),
+                "failed" => {
+                    let reason = processing
+                        .error
+                        .and_then(|error| error.message.or(error.name))
+                        .unwrap_or_default();
+                    return Err(format!("Media processing failed: {}", reason).into());
+                }
+                _ => {
+                    let wait = processing.check_after_secs.unwrap_or(1);
+                    tokio::time::sleep(Duration::from_secs(wait)).await;
+                    info = self.status(media_id).await?;
+                }
+            }
+        }
+        Err(format!("Media {} is still being processed", media_id).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+    use serde_json::json;
+    use wiremock::matchers::{header_exists, method, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn uploader(server: &MockServer, dir: &TempDir) -> ChunkedUpload {
+        let config = TwitterConfig {
+            api_key: "key".to_string(),
+            api_secret: "secret".to_string(),
+            access_token: "token".to_string(),
+            access_token_secret: "token secret".to_string(),
+            daily_tweets: None,
+        };
+        // The mock sends no rate limit headers, nothing is saved
+        let limits = RateLimiter::open(dir.path(), None).await;
+        let mut uploader = ChunkedUpload::new(&config, limits);
+        uploader.url = format!("{}/1.1/media/upload.json", server.uri());
+        uploader.chunk_size = 4;
+        uploader
+    }
+
+    async fn video(dir: &TempDir) -> PathBuf {
+        let file = dir.join("video.mp4");
+        tokio::fs::write(&file, b"0123456789").await.unwrap();
+        file
+    }
+
+    async fn mount_init(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(query_param("command", "INIT"))
+            .and(query_param("total_bytes", "10"))
+            .and(query_param("media_type", "video/mp4"))
+            .and(query_param("media_category", "tweet_video"))
+            .and(header_exists("Authorization"))
+            .respond_with(ResponseTemplate::new(202).set_body_json(
+                json!({"media_id": 710, "media_id_string": "710", "expires_after_secs": 86400}),
+            ))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    fn append(segment: u32) -> wiremock::MockBuilder {
+        Mock::given(method("POST"))
+            .and(query_param("command", "APPEND"))
+            .and(query_param("media_id", "710"))
+            .and(query_param("segment_index", segment.to_string()))
+    }
+
+    #[test]
+    fn test_media_category() {
+        let category = |mime: &str| media_category(&mime.parse().unwrap());
+        assert_eq!(category("video/mp4"), Some("tweet_video"));
+        assert_eq!(category("image/gif"), Some("tweet_gif"));
+        assert_eq!(category("image/jpeg"), Some("tweet_image"));
+        assert_eq!(category("application/pdf"), None);
+    }
+
+    #[tokio::test]
+    async fn test_upload_in_segments_and_wait_processing() {
+        let server = MockServer::start().await;
+        mount_init(&server).await;
+        for segment in 0..3 {
+            append(segment)
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(query_param("command", "FINALIZE"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "media_id_string": "710",
+                "processing_info": {"state": "pending", "check_after_secs": 0},
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(query_param("command", "STATUS"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "media_id_string": "710",
+                "processing_info": {"state": "in_progress", "check_after_secs": 0},
+            })))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(query_param("command", "STATUS"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "media_id_string": "710",
+                "processing_info": {"state": "succeeded"},
+            })))
+            .mount(&server)
+            .await;
+
+        let dir = TempDir::new("chunked");
+        let file = video(&dir).await;
+        let id = uploader(&server, &dir)
+            .await
+            .upload(&file, &"video/mp4".parse().unwrap())
+            .await;
+        assert_eq!(id.unwrap(), 710);
+    }
+
+    #[tokio::test]
+    async fn test_retry_resumes_upload() {
+        let server = MockServer::start().await;
+        mount_init(&server).await;
+        append(0)
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        append(1)
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        append(1)
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        append(2)
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(query_param("command", "FINALIZE"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "media_id_string": "710",
+                "processing_info": {"state": "failed", "error": {"message": "Unsupported video"}},
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = TempDir::new("resumed");
+        let file = video(&dir).await;
+        let uploader = uploader(&server, &dir).await;
+        let mp4: Mime = "video/mp4".parse().unwrap();
+        let error = uploader.upload(&file, &mp4).await.unwrap_err();
+        assert!(crate::twitter::types::is_retryable(&*error));
+        let error = uploader.upload(&file, &mp4).await.unwrap_err();
+        assert!(error.to_string().contains("Unsupported video"));
+        assert!(!crate::twitter::types::is_retryable(&*error));
+        assert!(uploader.sessions.lock().await.is_empty());
+    }
+}
@@ -1,25 +1,30 @@
+use crate::twitter::chunked_upload::ChunkedUpload;
+use crate::twitter::rate_limit::{Endpoint, RateLimiter};
+use crate::twitter::text::{weighted_length, MAX_TWEET_LENGTH};
 use crate::twitter::types::{Postable, TwitterBuilder, TwitterClient};
-use crate::types::Cfg;
+use crate::types::TwitterConfig;
 use async_trait::async_trait;
 use critter::auth::TwitterAuth;
 use critter::TwitterClient as Critter;
+use mime_guess::Mime;
 use std::error::Error;
 use std::path::Path;
-use mime_guess::Mime;
 
 #[derive(Clone)]
 pub struct CritterClient {
     builder: TwitterBuilder,
     client: Critter,
+    media: ChunkedUpload,
+    limits: RateLimiter,
 }
 
 impl CritterClient {
-    pub fn new(config: &Cfg) -> Self {
+    pub fn new(config: &TwitterConfig, limits: RateLimiter) -> Self {
         let auth = TwitterAuth::from_oa1uc(
-            &config.twitter.api_key,
-            &config.twitter.api_secret,
-            &config.twitter.access_token,
-            &config.twitter.access_token_secret,
+            &config.api_key,
+            &config.api_secret,
+            &config.access_token,
+            &config.access_token_secret,
         );
         let cli = match Critter::new(auth) {
             Ok(c) => c,
@@ -28,43 +33,69 @@ impl CritterClient {
         CritterClient {
             builder: TwitterBuilder::new(),
             client: cli,
+            media: ChunkedUpload::new(config, limits.clone()),
+            limits,
         }
     }
 }
 
 #[async_trait]
 impl Postable for CritterClient {
-    async fn upload_media(&mut self, file: &Path, media_type: &Mime) -> Result<u64, Box<dyn Error>> {
-        let filename = file
-            .file_name()
-            .map(|o| o.to_os_string().into_string().unwrap());
-        let path = file.to_str().expect("Error getting file path");
-        match self.client.upload_media(path, filename, Some(media_type.to_string())).await {
-            Ok(mut res) => Ok(res.id().parse().unwrap()),
-            Err(err) => Err(Box::try_from(err).unwrap()),
-        }
+    /// Uploads the media in segments, an upload interrupted by the rate limit or failing is
+    /// resumed where it stopped
+    async fn upload_media(
+        &mut self,
+        file: &Path,
+        media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        self.media.upload(file, media_type).await
     }
 
     async fn send(&mut self) -> Result<String, Box<dyn Error + Send + Sync>> {
-        match self
-            .client
-            .tweet(|tweet| {
-                let x = tweet.text(self.builder.text().as_str());
-                if !self.builder.media_ids().is_empty() {
-                    x.media(|mb| {
-                        for id in self.builder.media_ids() {
-                            mb.id(*id);
+        let length = weighted_length(&self.builder.text());
+        if length > MAX_TWEET_LENGTH {
+            return Err(format!(
+                "Tweet is too long: {} characters, the maximum is {}",
+                length, MAX_TWEET_LENGTH
+            )
+            .into());
+        }
+        let (client, builder) = (&self.client, &self.builder);
+        self.limits
+            .call(Endpoint::Tweet, || async {
+                let response = client
+                    .tweet(|tweet| {
+                        let x = tweet.text(builder.text().as_str());
+                        if !builder.media_ids().is_empty() {
+                            x.media(|mb| {
+                                for id in builder.media_ids() {
+                                    mb.id(*id);
+                                }
+                                mb
+                            });
+                        }
+                        if let Some(reply_to) = builder.reply_to() {
+                            x.reply(|rb| rb.in_reply_to_tweet_id(reply_to));
+                        }
+                        if let Some(quote) = builder.quote() {
+                            x.quote_tweet_id(quote);
                         }
-                        mb
-                    });
-                }
-                x
+                        x
+                    })
+                    .await?;
+                Ok(response.id().to_string())
+            })
+            .await
+    }
+
+    async fn delete(&mut self, tweet_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let client = &self.client;
+        self.limits
+            .call(Endpoint::Delete, || async {
+                client.delete_tweet(tweet_id).await?;
+                Ok(())
             })
             .await
-        {
-            Ok(response) => Ok(response.id().to_string()),
-            Err(e) => Err(Box::try_from(e).unwrap()),
-        }
     }
 }
 
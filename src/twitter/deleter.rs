@@ -0,0 +1,109 @@
+use crate::mapping::{Mapping, MappingStore};
+use crate::twitter::types::TwitterClient;
+use crate::types::{Runnable, Sink};
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+
+/// Deletes the tweets posted for deleted Telegram messages
+pub struct TwitterDeleter<C: TwitterClient> {
+    client: C,
+    mappings: MappingStore,
+    receiver: Option<Receiver<i32>>,
+}
+
+impl<C: TwitterClient> TwitterDeleter<C> {
+    pub fn new(client: C, mappings: MappingStore) -> Self {
+        TwitterDeleter {
+            client,
+            mappings,
+            receiver: None,
+        }
+    }
+}
+
+/// Deletes the tweets of a mapping, replies first so the thread never has dangling parts.
+/// Returns whether all of them were deleted
+pub(crate) async fn delete_tweets<C: TwitterClient>(client: &mut C, mapping: &Mapping) -> bool {
+    for tweet_id in mapping.tweet_ids.iter().rev() {
+        if let Err(e) = client.delete(tweet_id).await {
+            log::warn!(
+                "[Deleter] Error deleting tweet {} of {}: {:?}",
+                tweet_id,
+                mapping.tg_id,
+                e
+            );
+            return false;
+        }
+    }
+    true
+}
+
+impl<C: TwitterClient> Sink<i32> for TwitterDeleter<C> {
+    fn set_input(&mut self, receiver: Receiver<i32>) {
+        self.receiver = Some(receiver);
+    }
+}
+
+impl<C: TwitterClient> Runnable for TwitterDeleter<C> {
+    fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(tg_id) = self.receiver.as_mut().unwrap().recv().await {
+                let mapping = match self.mappings.get(tg_id).await {
+                    Some(m) if !m.deleted => m,
+                    _ => continue,
+                };
+
+                if delete_tweets(&mut self.client, &mapping).await {
+                    self.mappings.mark_deleted(tg_id).await;
+                    log::info!("Deleted tweets of telegram post {}", tg_id);
+                } else {
+                    log::warn!("Deletion of telegram post {} will be retried", tg_id);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::twitter::types::FakeClient;
+    use crate::types::Post;
+    use crate::util::TempDir;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_deletes_threads_last_tweet_first() {
+        let dir = TempDir::new("deleter");
+        let mappings = MappingStore::open(&Storage::Files, dir.path(), "chat").await;
+        for (id, tweets) in [(1, vec!["10", "11"]), (2, vec!["20"]), (3, vec!["30"])] {
+            let mut post = Post::new(id, String::new());
+            post.set_tweet_ids(tweets.iter().map(|t| t.to_string()).collect());
+            mappings.record(Mapping::from_post(&post, "chat")).await;
+        }
+        mappings.mark_deleted(3).await;
+
+        let client = FakeClient::new();
+        *client.failure.lock().unwrap() = Some("Timeout".to_string());
+        let mut failing = client.clone();
+        let mapping = mappings.get(2).await.unwrap();
+        assert!(!delete_tweets(&mut failing, &mapping).await);
+        *client.failure.lock().unwrap() = None;
+
+        let mut deleter = TwitterDeleter::new(client.clone(), mappings.clone());
+        let (sender, receiver) = mpsc::channel(10);
+        deleter.set_input(receiver);
+        let handle = deleter.run();
+        // Unknown and already deleted messages are skipped
+        for id in [1, 3, 4] {
+            sender.send(id).await.unwrap();
+        }
+        drop(sender);
+        handle.await.unwrap();
+
+        assert_eq!(*client.deleted.lock().unwrap(), vec!["11", "10"]);
+        assert!(mappings.get(1).await.unwrap().deleted);
+        assert!(!mappings.get(2).await.unwrap().deleted);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::mapping::MappingStore;
+use crate::twitter::deleter::delete_tweets;
+use crate::twitter::types::TwitterClient;
+use crate::types::{EditPolicy, Post, Processor, Runnable};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+
+const CORRECTION_PREFIX: &str = "Correction: ";
+
+/// Applies the edit policy to edited posts, new posts go through untouched
+///
+/// An edit whose content is the same as what was mirrored is dropped. Otherwise, depending on the
+/// policy, the old tweets are deleted and the post goes on to be posted again, or the post goes
+/// on without media as a reply to the old tweets.
+pub struct TwitterEditor<C: TwitterClient> {
+    client: C,
+    mappings: MappingStore,
+    policy: EditPolicy,
+    receiver: Option<Receiver<Post>>,
+    sender: Option<Sender<Post>>,
+}
+
+impl<C: TwitterClient> TwitterEditor<C> {
+    pub fn new(client: C, mappings: MappingStore, policy: EditPolicy) -> Self {
+        TwitterEditor {
+            client,
+            mappings,
+            policy,
+            receiver: None,
+            sender: None,
+        }
+    }
+
+    /// Returns the post to send down the pipeline, if any
+    async fn handle_edit(&mut self, mut post: Post) -> Option<Post> {
+        let mut mapping = self.mappings.get(post.id()).await.filter(|m| !m.deleted)?;
+        if mapping.content_hash == Some(post.content_hash()) {
+            log::info!(
+                "Telegram post {} was edited, content is unchanged",
+                post.id()
+            );
+            // Remember the edit so it is not checked again
+            mapping.edit_date = post.edit_date();
+            self.mappings.record(mapping).await;
+            return None;
+        }
+
+        match self.policy {
+            EditPolicy::Ignore => None,
+            EditPolicy::Repost => {
+                if !delete_tweets(&mut self.client, &mapping).await {
+                    log::warn!("Edit of telegram post {} will be retried", post.id());
+                    return None;
+                }
+                // So the deleter leaves them alone and the post is mirrored again if the
+                // repost fails and is requeued. The mapping is replaced once the new tweets are
+                // recorded
+                self.mappings.mark_deleted(post.id()).await;
+                post.set_reposted(true);
+                log::info!("Reposting edited telegram post {}", post.id());
+                Some(post)
+            }
+            EditPolicy::Correct => {
+                log::info!("Posting correction for telegram post {}", post.id());
+                post.set_text(format!("{}{}", CORRECTION_PREFIX, post.text()));
+                post.clear_attachments();
+                // Replying to itself makes the poster reply to the last tweet of the post
+                post.set_reply_to(Some(post.id()));
+                Some(post)
+            }
+        }
+    }
+}
+
+impl<C: TwitterClient> Processor<Post, Post> for TwitterEditor<C> {
+    fn set_input(&mut self, input: Receiver<Post>) {
+        self.receiver = Some(input);
+    }
+    fn set_output(&mut self, output: Sender<Post>) {
+        self.sender = Some(output);
+    }
+}
+
+impl<C: TwitterClient> Runnable for TwitterEditor<C> {
+    fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(post) = self.receiver.as_mut().unwrap().recv().await {
+                let post = if post.is_edit() {
+                    self.handle_edit(post).await
+                } else {
+                    Some(post)
+                };
+                if let Some(post) = post {
+                    self.sender
+                        .as_ref()
+                        .unwrap()
+                        .send(post)
+                        .await
+                        .expect("send");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dead_letter::DeadLetters;
+    use crate::journal::Journal;
+    use crate::mapping::Mapping;
+    use crate::storage::Storage;
+    use crate::twitter::deleter::TwitterDeleter;
+    use crate::twitter::gate::TwitterGate;
+    use crate::twitter::types::FakeClient;
+    use crate::types::Sink;
+    use crate::util::TempDir;
+    use tokio::sync::mpsc;
+
+    /// An editor of telegram post 1, mirrored as tweets 10 and 11 before it was edited
+    async fn editor(name: &str, policy: EditPolicy) -> (TwitterEditor<FakeClient>, TempDir) {
+        let dir = TempDir::new(&format!("editor-{}", name));
+        let mappings = MappingStore::open(&Storage::Files, dir.path(), "chat").await;
+        let mut mirrored = Post::new(1, "Helo".to_string());
+        mirrored.set_tweet_ids(vec!["10".to_string(), "11".to_string()]);
+        mappings.record(Mapping::from_post(&mirrored, "chat")).await;
+        (TwitterEditor::new(FakeClient::new(), mappings, policy), dir)
+    }
+
+    fn edit(text: &str) -> Post {
+        let mut post = Post::new(1, text.to_string());
+        post.set_edited(true);
+        post.set_edit_date(Some(100));
+        post
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_edit_is_dropped() {
+        let (mut editor, _dir) = editor("unchanged", EditPolicy::Repost).await;
+        assert!(editor.handle_edit(edit("Helo")).await.is_none());
+        assert!(editor.client.deleted.lock().unwrap().is_empty());
+        let mapping = editor.mappings.get(1).await.unwrap();
+        assert_eq!(mapping.edit_date, Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_ignore() {
+        let (mut editor, _dir) = editor("ignore", EditPolicy::Ignore).await;
+        assert!(editor.handle_edit(edit("Hello")).await.is_none());
+        assert!(editor.client.deleted.lock().unwrap().is_empty());
+        assert!(!editor.mappings.get(1).await.unwrap().deleted);
+    }
+
+    #[tokio::test]
+    async fn test_repost() {
+        let (mut editor, _dir) = editor("repost", EditPolicy::Repost).await;
+        *editor.client.failure.lock().unwrap() = Some("Timeout".to_string());
+        assert!(editor.handle_edit(edit("Hello")).await.is_none());
+
+        *editor.client.failure.lock().unwrap() = None;
+        let post = editor.handle_edit(edit("Hello")).await.unwrap();
+        assert!(post.is_repost());
+        assert_eq!(post.text(), "Hello");
+        assert_eq!(*editor.client.deleted.lock().unwrap(), vec!["11", "10"]);
+        assert!(editor.mappings.get(1).await.unwrap().deleted);
+    }
+
+    #[tokio::test]
+    async fn test_failed_repost_is_mirrored_again() {
+        let (mut editor, dir) = editor("failed-repost", EditPolicy::Repost).await;
+        editor.handle_edit(edit("Hello")).await.unwrap();
+        // The repost fails before its tweets are recorded, then it is requeued
+        let dead_letters = DeadLetters::open(&Storage::Files, dir.path(), "chat").await;
+        dead_letters.add(1, "poster", &"Timeout").await;
+        dead_letters.requeue(Some(1)).await;
+
+        // The old tweets are not deleted again
+        let mut deleter = TwitterDeleter::new(editor.client.clone(), editor.mappings.clone());
+        let (sender, receiver) = mpsc::channel(10);
+        deleter.set_input(receiver);
+        let handle = deleter.run();
+        sender.send(1).await.unwrap();
+        drop(sender);
+        handle.await.unwrap();
+        assert_eq!(*editor.client.deleted.lock().unwrap(), vec!["11", "10"]);
+
+        let journal = Journal::open(&Storage::Files, dir.path()).await;
+        let mut gate = TwitterGate::new("twitter", editor.mappings.clone(), dead_letters, journal);
+        let (input, receiver) = mpsc::channel(10);
+        let (sender, mut output) = mpsc::channel(10);
+        gate.set_input(receiver);
+        gate.set_output(sender);
+        let handle = gate.run();
+        input.send(Post::new(1, "Hello".to_string())).await.unwrap();
+        drop(input);
+        handle.await.unwrap();
+        assert_eq!(output.recv().await.unwrap().id(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_correct() {
+        let (mut editor, _dir) = editor("correct", EditPolicy::Correct).await;
+        let edited = edit("Hello");
+        let post = editor.handle_edit(edited.clone()).await.unwrap();
+        assert_eq!(post.text(), "Correction: Hello");
+        assert!(post.attachments().is_empty());
+        assert_eq!(post.reply_to(), Some(1));
+        assert!(!post.is_repost());
+        // The next edit is compared with the content as published
+        assert_eq!(post.content_hash(), edited.content_hash());
+        assert!(editor.client.deleted.lock().unwrap().is_empty());
+    }
+}
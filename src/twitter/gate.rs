@@ -0,0 +1,154 @@
+use crate::dead_letter::{DeadLetterStatus, DeadLetters};
+use crate::journal::{Journal, Step};
+use crate::mapping::MappingStore;
+use crate::types::{Post, Processor, Runnable};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+
+/// Heads the branch of a destination, letting through the posts still to be mirrored to it
+///
+/// A post is mirrored again when another destination resumes it or requeues it. The ones this
+/// destination already mirrored, or gave up on and were not requeued for it, are dropped so they
+/// are not posted twice. Edits go through, the editor checks them against the mapping, and so do
+/// the posts whose tweets were deleted to repost an edit.
+pub struct TwitterGate {
+    destination: String,
+    mappings: MappingStore,
+    dead_letters: DeadLetters,
+    journal: Journal,
+    receiver: Option<Receiver<Post>>,
+    sender: Option<Sender<Post>>,
+}
+
+impl TwitterGate {
+    pub fn new(
+        destination: &str,
+        mappings: MappingStore,
+        dead_letters: DeadLetters,
+        journal: Journal,
+    ) -> Self {
+        TwitterGate {
+            destination: destination.to_string(),
+            mappings,
+            dead_letters,
+            journal,
+            receiver: None,
+            sender: None,
+        }
+    }
+
+    async fn accepts(&self, post: &Post) -> bool {
+        if post.is_edit() {
+            return true;
+        }
+        let mapping = self.mappings.get(post.id()).await;
+        if mapping.filter(|m| !m.deleted).is_some() {
+            log::info!(
+                "Telegram post {} was already mirrored to {}",
+                post.id(),
+                self.destination
+            );
+            // It may have been interrupted before it was marked as done
+            self.journal.record(post, Step::Done, |_| {}).await;
+            return false;
+        }
+        let given_up = self
+            .dead_letters
+            .with_status(DeadLetterStatus::Failed)
+            .await
+            .iter()
+            .any(|letter| letter.tg_id == post.id());
+        if given_up {
+            log::info!(
+                "Telegram post {} failed on {}, it is not mirrored there unless requeued",
+                post.id(),
+                self.destination
+            );
+        }
+        !given_up
+    }
+}
+
+impl Processor<Post, Post> for TwitterGate {
+    fn set_input(&mut self, input: Receiver<Post>) {
+        self.receiver = Some(input);
+    }
+    fn set_output(&mut self, output: Sender<Post>) {
+        self.sender = Some(output);
+    }
+}
+
+impl Runnable for TwitterGate {
+    fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(post) = self.receiver.as_mut().unwrap().recv().await {
+                if !self.accepts(&post).await {
+                    continue;
+                }
+                self.sender
+                    .as_ref()
+                    .unwrap()
+                    .send(post)
+                    .await
+                    .expect("send");
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapping::Mapping;
+    use crate::storage::Storage;
+    use crate::util::TempDir;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_drops_posts_already_handled() {
+        let dir = TempDir::new("gate");
+
+        let storage = Storage::Files;
+        let mappings = MappingStore::open(&storage, dir.path(), "chat").await;
+        let dead_letters = DeadLetters::open(&storage, dir.path(), "chat").await;
+        let journal = Journal::open(&storage, dir.path()).await;
+
+        let mirrored = Post::new(1, "mirrored".to_string());
+        journal.record(&mirrored, Step::Posted, |_| {}).await;
+        mappings.record(Mapping::from_post(&mirrored, "chat")).await;
+        let mut edit = mirrored.clone();
+        edit.set_edited(true);
+        let failed = Post::new(2, "failed".to_string());
+        dead_letters.add(2, "poster", &"Tweet is too long").await;
+        let requeued = Post::new(3, "requeued".to_string());
+        dead_letters.add(3, "poster", &"Timeout").await;
+        dead_letters.requeue(Some(3)).await;
+        let new = Post::new(4, "new".to_string());
+        // Its tweets were deleted to repost an edit, then the repost failed and was requeued
+        let reposted = Post::new(5, "reposted".to_string());
+        mappings.record(Mapping::from_post(&reposted, "chat")).await;
+        mappings.mark_deleted(5).await;
+        dead_letters.add(5, "poster", &"Timeout").await;
+        dead_letters.requeue(Some(5)).await;
+
+        let mut gate = TwitterGate::new("twitter", mappings, dead_letters, journal.clone());
+        let (input, receiver) = mpsc::channel(10);
+        let (sender, mut output) = mpsc::channel(10);
+        gate.set_input(receiver);
+        gate.set_output(sender);
+        let handle = gate.run();
+        for post in [mirrored, edit, failed, requeued, new, reposted] {
+            input.send(post).await.unwrap();
+        }
+        drop(input);
+        handle.await.unwrap();
+
+        let mut passed = vec![];
+        while let Some(post) = output.recv().await {
+            passed.push((post.id(), post.is_edit()));
+        }
+        assert_eq!(passed, vec![(1, true), (3, false), (4, false), (5, false)]);
+        // The post mirrored before an interruption is not resumed again
+        assert!(journal.pending().await.is_empty());
+    }
+}
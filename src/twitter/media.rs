@@ -0,0 +1,76 @@
+use mime_guess::{mime, Mime};
+
+/// Maximum number of images in a single tweet
+pub const MAX_IMAGES_PER_TWEET: usize = 4;
+
+/// Groups the uploaded media in the sets a single tweet accepts, keeping their order: up to four
+/// images, or a video or GIF alone
+///
+/// # Arguments
+///  * `media` - The Twitter media ids and the type of the media uploaded
+pub fn pack_media(media: &[(u64, Mime)]) -> Vec<Vec<u64>> {
+    let mut groups = vec![];
+    let mut images = vec![];
+    for (id, media_type) in media {
+        if is_image(media_type) {
+            images.push(*id);
+            if images.len() == MAX_IMAGES_PER_TWEET {
+                groups.push(std::mem::take(&mut images));
+            }
+        } else {
+            if !images.is_empty() {
+                groups.push(std::mem::take(&mut images));
+            }
+            groups.push(vec![*id]);
+        }
+    }
+    if !images.is_empty() {
+        groups.push(images);
+    }
+    groups
+}
+
+/// Still images, GIFs are animated and go alone like videos
+fn is_image(media_type: &Mime) -> bool {
+    media_type.type_() == mime::IMAGE && media_type.subtype() != mime::GIF
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn media(types: &[&Mime]) -> Vec<(u64, Mime)> {
+        types
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (i as u64, (*m).clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_images_in_groups_of_four() {
+        let album = media(&[&mime::IMAGE_JPEG; 10]);
+        assert_eq!(
+            pack_media(&album),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]
+        );
+        assert!(pack_media(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_videos_and_gifs_go_alone() {
+        let mp4: Mime = "video/mp4".parse().unwrap();
+        let album = media(&[
+            &mime::IMAGE_JPEG,
+            &mime::IMAGE_PNG,
+            &mp4,
+            &mime::IMAGE_JPEG,
+            &mime::IMAGE_GIF,
+            &mp4,
+        ]);
+        assert_eq!(
+            pack_media(&album),
+            vec![vec![0, 1], vec![2], vec![3], vec![4], vec![5]]
+        );
+    }
+}
@@ -1,4 +1,13 @@
+pub(crate) mod chunked_upload;
 pub(crate) mod critter_client;
+pub(crate) mod deleter;
+pub(crate) mod editor;
+pub(crate) mod gate;
+pub(crate) mod media;
+pub(crate) mod oauth;
 pub(crate) mod poster;
+pub(crate) mod rate_limit;
+pub(crate) mod text;
+pub(crate) mod thread;
 pub(crate) mod types;
 pub(crate) mod uploader;
@@ -0,0 +1,140 @@
+use crate::types::TwitterConfig;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha1::Sha1;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The characters OAuth encodes, all but the unreserved ones of RFC 3986
+const ENCODED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+static NONCES: AtomicU64 = AtomicU64::new(0);
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, ENCODED).to_string()
+}
+
+/// Signs the requests to the Twitter API with the OAuth 1.0a credentials of the account
+#[derive(Clone)]
+pub struct OAuth {
+    consumer_key: String,
+    consumer_secret: String,
+    token: String,
+    token_secret: String,
+}
+
+impl OAuth {
+    pub fn new(config: &TwitterConfig) -> Self {
+        OAuth {
+            consumer_key: config.api_key.clone(),
+            consumer_secret: config.api_secret.clone(),
+            token: config.access_token.clone(),
+            token_secret: config.access_token_secret.clone(),
+        }
+    }
+
+    /// The `Authorization` header of a request
+    ///
+    /// # Arguments
+    ///  * `url` - The URL of the request, without the query
+    ///  * `params` - The query and form parameters, the parts of a multipart body are not signed
+    pub fn authorization(&self, method: &str, url: &str, params: &[(&str, String)]) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time");
+        let nonce = format!(
+            "{:x}{:x}",
+            crate::util::stable_hash(timestamp.as_nanos().to_le_bytes().iter()),
+            NONCES.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut oauth = vec![
+            ("oauth_consumer_key", self.consumer_key.clone()),
+            ("oauth_nonce", nonce),
+            ("oauth_signature_method", "HMAC-SHA1".to_string()),
+            ("oauth_timestamp", timestamp.as_secs().to_string()),
+            ("oauth_token", self.token.clone()),
+            ("oauth_version", "1.0".to_string()),
+        ];
+        let signed: Vec<(&str, String)> = params.iter().cloned().chain(oauth.clone()).collect();
+        oauth.push(("oauth_signature", self.signature(method, url, &signed)));
+        let header: Vec<String> = oauth
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, encode(value)))
+            .collect();
+        format!("OAuth {}", header.join(", "))
+    }
+
+    /// The HMAC-SHA1 signature of a request, `params` include the `oauth_` ones
+    fn signature(&self, method: &str, url: &str, params: &[(&str, String)]) -> String {
+        let mut encoded: Vec<(String, String)> = params
+            .iter()
+            .map(|(key, value)| (encode(key), encode(value)))
+            .collect();
+        encoded.sort();
+        let params: Vec<String> = encoded
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        let base = format!(
+            "{}&{}&{}",
+            method.to_uppercase(),
+            encode(url),
+            encode(&params.join("&"))
+        );
+        let key = format!(
+            "{}&{}",
+            encode(&self.consumer_secret),
+            encode(&self.token_secret)
+        );
+        let mut mac = Hmac::<Sha1>::new_from_slice(key.as_bytes()).expect("HMAC key");
+        mac.update(base.as_bytes());
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // The example of the Twitter documentation on creating a signature
+        let oauth = OAuth {
+            consumer_key: "xvz1evFS4wEEPTGEFPHBog".to_string(),
+            consumer_secret: "kAcSOqF21Fu85e7zjz7ZN2U4ZRhfV3WpwPAoE3Z7kBw".to_string(),
+            token: "370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb".to_string(),
+            token_secret: "LswwdoUaIvS8ltyTt5jkRh4J50vUPVVHtR2YPi5kE".to_string(),
+        };
+        let params = [
+            (
+                "status",
+                "Hello Ladies + Gentlemen, a signed OAuth request!",
+            ),
+            ("include_entities", "true"),
+            ("oauth_consumer_key", "xvz1evFS4wEEPTGEFPHBog"),
+            ("oauth_nonce", "kYjzVBB8Y0ZFabxSWbWovY3uYSQ2pTgmZeNu2VS4cg"),
+            ("oauth_signature_method", "HMAC-SHA1"),
+            ("oauth_timestamp", "1318622958"),
+            (
+                "oauth_token",
+                "370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb",
+            ),
+            ("oauth_version", "1.0"),
+        ]
+        .map(|(key, value)| (key, value.to_string()));
+        assert_eq!(
+            oauth.signature(
+                "post",
+                "https://api.twitter.com/1.1/statuses/update.json",
+                &params
+            ),
+            "hCtSmYh+iHYCEqBWrE7C7hYmtUk="
+        );
+    }
+}
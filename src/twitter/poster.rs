@@ -1,22 +1,153 @@
-use crate::twitter::types::TwitterClient;
+use crate::dead_letter::DeadLetters;
+use crate::journal::{Journal, Step};
+use crate::mapping::MappingStore;
+use crate::retry::RetryPolicy;
+use crate::twitter::media::pack_media;
+use crate::twitter::thread::split_text;
+use crate::twitter::types::{is_retryable, TwitterClient};
 use crate::types::{Post, Processor, Runnable};
+use std::collections::HashMap;
+use std::error::Error;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
 
 pub struct TwitterPoster<C: TwitterClient> {
     client: C,
+    mappings: MappingStore,
+    // Last tweet posted for each message in this run, the mapping store may not have them yet
+    posted: HashMap<i32, String>,
+    retry: RetryPolicy,
+    dead_letters: DeadLetters,
+    journal: Journal,
     sender: Option<Sender<Post>>,
     receiver: Option<Receiver<Post>>,
 }
 
 impl<C: TwitterClient> TwitterPoster<C> {
-    pub fn new(client: C) -> Self {
+    pub fn new(
+        client: C,
+        mappings: MappingStore,
+        retry: RetryPolicy,
+        dead_letters: DeadLetters,
+        journal: Journal,
+    ) -> Self {
         TwitterPoster {
             client,
+            mappings,
+            posted: HashMap::new(),
+            retry,
+            dead_letters,
+            journal,
             receiver: None,
             sender: None,
         }
     }
+
+    /// The tweet a post should reply to, i.e. the last tweet of the thread posted for the
+    /// Telegram message it replies to
+    async fn reply_target(&self, msg: &Post) -> Option<String> {
+        let parent = msg.reply_to()?;
+        let target = match self.posted.get(&parent) {
+            Some(tweet_id) => Some(tweet_id.clone()),
+            None => self
+                .mappings
+                .get(parent)
+                .await
+                .filter(|m| !m.deleted)
+                .and_then(|m| m.tweet_ids.last().cloned()),
+        };
+        if target.is_none() {
+            log::info!(
+                "Telegram post {} replies to {}, which was not mirrored",
+                msg.id(),
+                parent
+            );
+        }
+        target
+    }
+
+    /// Posts the message as a thread, each following tweet replies to the previous one. The text
+    /// starts in the first tweet, and the media are spread from the first tweet on in the sets a
+    /// tweet accepts, the thread is numbered over all of its tweets. The first tweet replies to
+    /// the mirror of the Telegram message the post replies to, if any. Each tweet is retried
+    /// according to the retry policy, if one still fails the tweets already sent are deleted, so
+    /// the post can be sent again as a whole. A resumed thread goes on from the last tweet sent.
+    /// Returns the ids of the tweets sent
+    async fn post_thread(
+        &mut self,
+        msg: &Post,
+    ) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let media = pack_media(msg.tw_media());
+        let parts = split_text(msg.text(), self.client.max_length(), media.len(), |text| {
+            self.client.length(text)
+        });
+        let mut tweet_ids: Vec<String> = vec![];
+        match self.journal.resume(msg).await {
+            Some(entry) if entry.step == Step::Posted => return Ok(entry.tweet_ids),
+            Some(entry) if entry.step == Step::Posting => tweet_ids = entry.tweet_ids,
+            _ => {}
+        }
+        let mut reply_to = match tweet_ids.last() {
+            Some(last) => {
+                log::info!("Resuming thread of {} after tweet {}", msg.id(), last);
+                Some(last.clone())
+            }
+            None => self.reply_target(msg).await,
+        };
+        for (i, part) in parts.iter().enumerate().skip(tweet_ids.len()) {
+            let builder = self.client.new_builder();
+            builder.set_text(part.clone());
+            for attachment in media.get(i).into_iter().flatten() {
+                builder.add_media(*attachment);
+            }
+            builder.set_reply_to(reply_to.take());
+            builder.set_source(msg.id(), msg.edit_date(), i);
+            if i == 0 {
+                builder.set_quote(msg.quote().cloned());
+            }
+
+            let what = format!("[Poster] Tweet {} for {}", i + 1, msg.id());
+            let mut attempt = 0;
+            let result = loop {
+                attempt += 1;
+                match self.client.send().await {
+                    Err(e) if is_retryable(&*e) && self.retry.wait(attempt, &what, &e).await => {
+                        continue
+                    }
+                    result => break result,
+                }
+            };
+
+            match result {
+                Ok(id) => {
+                    reply_to = Some(id.clone());
+                    tweet_ids.push(id);
+                    self.journal
+                        .record(msg, Step::Posting, |entry| {
+                            entry.tweet_ids = tweet_ids.clone()
+                        })
+                        .await;
+                }
+                Err(e) => {
+                    for tweet_id in tweet_ids.iter().rev() {
+                        if let Err(err) = self.client.delete(tweet_id).await {
+                            log::warn!(
+                                "[Poster] Error deleting tweet {} of incomplete thread for {}: {}",
+                                tweet_id,
+                                msg.id(),
+                                err
+                            );
+                        }
+                    }
+                    self.journal
+                        .record(msg, Step::Uploaded, |entry| entry.tweet_ids.clear())
+                        .await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(tweet_ids)
+    }
 }
 
 impl<C: TwitterClient> Processor<Post, Post> for TwitterPoster<C> {
@@ -36,36 +167,39 @@ impl<C: TwitterClient> Runnable for TwitterPoster<C> {
                     None => {
                         break;
                     }
-                    Some(msg) => {
-                        let text = msg.text().to_string();
-                        let builder = self.client.new_builder();
-                        builder.set_text(text);
-
-                        for attachment in msg.tw_attachments() {
-                            builder.add_media(attachment.clone());
+                    Some(mut msg) => {
+                        if msg.text().trim().is_empty() && msg.tw_attachments().is_empty() {
+                            log::info!("Ignored telegram post {} with no text and media", msg.id());
+                            // Nothing is left to do, it is not resumed by the next runs
+                            self.journal.record(&msg, Step::Done, |_| {}).await;
+                            continue;
                         }
 
-                        if builder.text().is_empty() && builder.media_ids().is_empty() {
-                            log::info!("Ignored telegram post {} with no text and media", msg.id());
-                        } else {
-                            match self.client.send().await {
-                                Ok(_) => {
-                                    let id = msg.id();
-                                    self.sender.as_ref().unwrap().send(msg).await.expect("TODO");
-                                    log::info!("Successfully posted tweet for {}", id);
-                                }
-                                Err(e) if e.to_string().contains("Your media IDs are invalid") => {
-                                    log::warn!("Error sending tweet {}, the media is unsupported. This will not be retried.", msg.id());
-                                    self.sender
-                                        .as_ref()
-                                        .unwrap()
-                                        .send(msg)
-                                        .await
-                                        .expect("send");
-                                }
-                                Err(e) => {
-                                    panic!("[Poster] Error sending Tweet for {}:{:?}", msg.id(), e);
+                        match self.post_thread(&msg).await {
+                            Ok(tweet_ids) => {
+                                let id = msg.id();
+                                let count = tweet_ids.len();
+                                self.journal
+                                    .record(&msg, Step::Posted, |entry| {
+                                        entry.tweet_ids = tweet_ids.clone()
+                                    })
+                                    .await;
+                                if let Some(last) = tweet_ids.last() {
+                                    self.posted.insert(id, last.clone());
                                 }
+                                msg.set_tweet_ids(tweet_ids);
+                                self.sender.as_ref().unwrap().send(msg).await.expect("TODO");
+                                log::info!("Successfully posted {} tweet(s) for {}", count, id);
+                            }
+                            Err(e) if e.to_string().contains("Your media IDs are invalid") => {
+                                log::warn!("Error sending tweet {}, the media is unsupported. This will not be retried.", msg.id());
+                                self.journal
+                                    .record(&msg, Step::Posted, |entry| entry.tweet_ids.clear())
+                                    .await;
+                                self.sender.as_ref().unwrap().send(msg).await.expect("send");
+                            }
+                            Err(e) => {
+                                self.dead_letters.add(msg.id(), "post", &e).await;
                             }
                         }
                     }
@@ -74,3 +208,93 @@ impl<C: TwitterClient> Runnable for TwitterPoster<C> {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::twitter::types::FakeClient;
+    use crate::util::TempDir;
+    use mime_guess::mime;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_post_with_nothing_to_tweet_is_done() {
+        let dir = TempDir::new("poster");
+        let storage = Storage::Files;
+        let journal = Journal::open(&storage, dir.path()).await;
+
+        // Only had unsupported media, dropped by the uploader
+        let empty = Post::new(1, " ".to_string());
+        journal.record(&empty, Step::Uploaded, |_| {}).await;
+        let post = Post::new(2, "Hello".to_string());
+        journal.record(&post, Step::Uploaded, |_| {}).await;
+
+        let client = FakeClient::new();
+        let mut poster = TwitterPoster::new(
+            client.clone(),
+            MappingStore::open(&storage, dir.path(), "chat").await,
+            RetryPolicy::default(),
+            DeadLetters::open(&storage, dir.path(), "chat").await,
+            journal.clone(),
+        );
+        let (input, receiver) = mpsc::channel(10);
+        let (sender, mut output) = mpsc::channel(10);
+        poster.set_input(receiver);
+        poster.set_output(sender);
+        let handle = poster.run();
+        input.send(empty).await.unwrap();
+        input.send(post).await.unwrap();
+        drop(input);
+        handle.await.unwrap();
+
+        assert_eq!(
+            output.recv().await.unwrap().tweet_ids(),
+            &vec!["100".to_string()]
+        );
+        assert!(output.recv().await.is_none());
+        assert_eq!(client.texts(), vec!["Hello".to_string()]);
+        // The post that was posted is done once persisted
+        assert_eq!(journal.pending().await, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_media_tweets_are_numbered() {
+        let dir = TempDir::new("poster-media");
+        let storage = Storage::Files;
+        let journal = Journal::open(&storage, dir.path()).await;
+
+        let mut album = Post::new(1, "Hello".to_string());
+        for id in 0..5 {
+            album.add_twitter_attachment(id, mime::IMAGE_JPEG);
+        }
+        let mut unsupported = Post::new(2, "Hello".to_string());
+        unsupported.add_twitter_attachment(9, mime::IMAGE_JPEG);
+
+        let client = FakeClient::new();
+        let mut poster = TwitterPoster::new(
+            client.clone(),
+            MappingStore::open(&storage, dir.path(), "chat").await,
+            RetryPolicy::default(),
+            DeadLetters::open(&storage, dir.path(), "chat").await,
+            journal.clone(),
+        );
+        let (input, receiver) = mpsc::channel(10);
+        let (sender, mut output) = mpsc::channel(10);
+        poster.set_input(receiver);
+        poster.set_output(sender);
+        let handle = poster.run();
+        input.send(album).await.unwrap();
+        output.recv().await.unwrap();
+        assert_eq!(client.texts(), vec!["Hello 1/2", "2/2"]);
+
+        *client.failure.lock().unwrap() = Some("Your media IDs are invalid".to_string());
+        input.send(unsupported.clone()).await.unwrap();
+        drop(input);
+        handle.await.unwrap();
+        // Forwarded without tweets, like a post that was posted
+        assert!(output.recv().await.unwrap().tweet_ids().is_empty());
+        let entry = journal.resume(&unsupported).await.unwrap();
+        assert_eq!(entry.step, Step::Posted);
+    }
+}
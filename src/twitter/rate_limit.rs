@@ -0,0 +1,445 @@
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+pub(crate) const RATE_LIMIT_FILE: &str = "rate_limits";
+
+/// Window of the Twitter rate limits, used when a response does not tell when it resets
+const DEFAULT_WINDOW: u64 = 15 * 60;
+
+/// Window of the daily tweet cap
+const DAY: u64 = 24 * 60 * 60;
+
+/// How many times a request rejected for exceeding the rate limit is sent again
+const MAX_RATE_LIMITED: u32 = 3;
+
+/// The headers of the quota of a response, the daily one is only sent for tweets
+const QUOTA_HEADERS: [(&str, &str); 2] = [
+    ("x-rate-limit-remaining", "x-rate-limit-reset"),
+    ("x-user-limit-24hour-remaining", "x-user-limit-24hour-reset"),
+];
+
+/// The Twitter endpoints with their own quota
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Endpoint {
+    Tweet,
+    Delete,
+    Media,
+    /// The daily cap of tweets configured, taken along with `Tweet`
+    DailyTweets,
+}
+
+/// What is left of the quota of an endpoint until `reset`, a Unix timestamp
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Quota {
+    remaining: Option<u32>,
+    reset: u64,
+}
+
+/// Keeps the requests to Twitter within the rate limits, pausing until the quota of an endpoint
+/// resets instead of failing
+///
+/// The quotas are learnt from the `x-rate-limit-remaining` and `x-rate-limit-reset` headers of
+/// the responses of the media uploads, from the rate limit errors of the tweets and deletions
+/// and, for tweets, from the daily cap configured. They are saved in the `rate_limits` file of
+/// the data dir so they survive between runs. Clones share the same quotas.
+#[derive(Clone)]
+pub struct RateLimiter {
+    path: PathBuf,
+    daily_tweets: Option<u32>,
+    quotas: Arc<Mutex<BTreeMap<Endpoint, Quota>>>,
+}
+
+impl RateLimiter {
+    /// # Arguments
+    ///  * `data_dir` - Where the quotas are saved
+    ///  * `daily_tweets` - The number of tweets the account may send in 24 hours, if limited
+    pub async fn open(data_dir: &str, daily_tweets: Option<u32>) -> RateLimiter {
+        let mut path = PathBuf::from(data_dir);
+        path.push(RATE_LIMIT_FILE);
+        let quotas = match fs::read_to_string(&path).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid rate limits file: {}", e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        RateLimiter {
+            path,
+            daily_tweets,
+            quotas: Arc::new(Mutex::new(quotas)),
+        }
+    }
+
+    /// Takes one request from the quota of the endpoint, waiting for it to reset if there is
+    /// nothing left
+    pub async fn acquire(&self, endpoint: Endpoint) {
+        if endpoint == Endpoint::Tweet && self.daily_tweets.is_some() {
+            self.take(Endpoint::DailyTweets).await;
+        }
+        self.take(endpoint).await;
+    }
+
+    async fn take(&self, endpoint: Endpoint) {
+        loop {
+            let wait = {
+                let mut quotas = self.quotas.lock().await;
+                let now = now();
+                let quota = quotas
+                    .entry(endpoint)
+                    .or_insert_with(|| self.fresh_quota(endpoint, now));
+                if quota.reset <= now {
+                    *quota = self.fresh_quota(endpoint, now);
+                }
+                match quota.remaining {
+                    Some(0) => quota.reset - now,
+                    Some(remaining) => {
+                        quota.remaining = Some(remaining - 1);
+                        self.save(&quotas).await;
+                        return;
+                    }
+                    None => return,
+                }
+            };
+            log::warn!(
+                "Rate limit of {:?} reached, pausing for {} seconds",
+                endpoint,
+                wait
+            );
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+        }
+    }
+
+    /// Sends a request to an endpoint within its quota, which is updated from the response.
+    /// A request rejected for exceeding the rate limit is sent again once the quota resets, up
+    /// to `MAX_RATE_LIMITED` times
+    ///
+    /// # Arguments
+    ///  * `request` - Builds the request, once per attempt
+    pub async fn send<F: Fn() -> RequestBuilder>(
+        &self,
+        endpoint: Endpoint,
+        request: F,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            self.acquire(endpoint).await;
+            let response = request().send().await?;
+            let status = response.status();
+            if status != StatusCode::TOO_MANY_REQUESTS {
+                self.update(endpoint, response.headers()).await;
+                return Ok(response);
+            }
+            if !self
+                .rejected(endpoint, reset_of(response.headers()), attempts)
+                .await
+            {
+                let body = response.text().await.unwrap_or_default();
+                return Err(still_exceeded(endpoint, attempts, &body));
+            }
+        }
+    }
+
+    /// Makes a call to an endpoint within its quota through a client that doesn't expose the
+    /// responses. A call failing for exceeding the rate limit, as told by its error, is made again
+    /// once the quota resets, up to `MAX_RATE_LIMITED` times
+    pub async fn call<T, F, Fut>(
+        &self,
+        endpoint: Endpoint,
+        mut call: F,
+    ) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            self.acquire(endpoint).await;
+            let error = match call().await {
+                Err(e) if is_rate_limited(&e.to_string()) => e.to_string(),
+                result => return result,
+            };
+            if !self
+                .rejected(endpoint, reset_quoted(&error), attempts)
+                .await
+            {
+                return Err(still_exceeded(endpoint, attempts, &error));
+            }
+        }
+    }
+
+    /// Records a request rejected for exceeding the rate limit, returns whether it may be sent
+    /// again once the quota resets
+    async fn rejected(&self, endpoint: Endpoint, reset: Option<u64>, attempts: u32) -> bool {
+        self.limited(endpoint, reset).await;
+        if attempts > MAX_RATE_LIMITED {
+            return false;
+        }
+        log::warn!("Rate limit of {:?} exceeded", endpoint);
+        true
+    }
+
+    /// Records the quota left according to the headers of a response
+    pub async fn update(&self, endpoint: Endpoint, headers: &HeaderMap) {
+        let Some(quota) = quota_of(headers) else {
+            return;
+        };
+        let mut quotas = self.quotas.lock().await;
+        if quotas.get(&endpoint) != Some(&quota) {
+            quotas.insert(endpoint, quota);
+            self.save(&quotas).await;
+        }
+    }
+
+    /// Records that Twitter rejected a request for exceeding the rate limit, which resets at the
+    /// given Unix timestamp, if known. A reset already passed is sent again right away
+    pub async fn limited(&self, endpoint: Endpoint, reset: Option<u64>) {
+        let mut quotas = self.quotas.lock().await;
+        let now = now();
+        let reset = match reset {
+            Some(reset) => reset.max(now),
+            None => now + DEFAULT_WINDOW,
+        };
+        quotas.insert(
+            endpoint,
+            Quota {
+                remaining: Some(0),
+                reset,
+            },
+        );
+        self.save(&quotas).await;
+    }
+
+    fn fresh_quota(&self, endpoint: Endpoint, now: u64) -> Quota {
+        match (endpoint, self.daily_tweets) {
+            (Endpoint::DailyTweets, Some(daily)) => Quota {
+                remaining: Some(daily),
+                reset: now + DAY,
+            },
+            _ => Quota {
+                remaining: None,
+                reset: 0,
+            },
+        }
+    }
+
+    async fn save(&self, quotas: &BTreeMap<Endpoint, Quota>) {
+        let content = serde_json::to_string(quotas).expect("Serialize rate limits");
+        if let Err(e) = fs::write(&self.path, content).await {
+            log::warn!("Error saving rate limits: {}", e);
+        }
+    }
+}
+
+fn still_exceeded(endpoint: Endpoint, attempts: u32, error: &str) -> Box<dyn Error + Send + Sync> {
+    format!(
+        "Rate limit of {:?} still exceeded after {} attempts: {}",
+        endpoint, attempts, error
+    )
+    .into()
+}
+
+/// Whether the call failed for exceeding the rate limit
+pub fn is_rate_limited(error: &str) -> bool {
+    error.contains("429") || error.contains("Too Many Requests")
+}
+
+/// The Unix timestamp at which the rate limit resets, read from the `x-rate-limit-reset` or the
+/// `x-user-limit-24hour-reset` headers quoted in the error, if any
+fn reset_quoted(error: &str) -> Option<u64> {
+    let lower = error.to_ascii_lowercase();
+    QUOTA_HEADERS
+        .iter()
+        .filter_map(|(_, header)| {
+            let value = &lower[lower.find(header)? + header.len()..];
+            let digits: String = value
+                .trim_start_matches(|c: char| !c.is_ascii_digit())
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect();
+            digits.parse().ok()
+        })
+        .max()
+}
+
+fn header_number(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// The tightest quota announced by the headers of a response, if any
+fn quota_of(headers: &HeaderMap) -> Option<Quota> {
+    let number = |name: &str| header_number(headers, name);
+    QUOTA_HEADERS
+        .iter()
+        .filter_map(|(remaining, reset)| {
+            Some(Quota {
+                remaining: Some(number(remaining)?.try_into().unwrap_or(u32::MAX)),
+                reset: number(reset)?,
+            })
+        })
+        .min_by_key(|quota| quota.remaining)
+}
+
+/// When the exhausted quota of a rejected request resets, the latest one if it is not told
+/// which quota was exceeded
+fn reset_of(headers: &HeaderMap) -> Option<u64> {
+    QUOTA_HEADERS
+        .iter()
+        .filter(|(remaining, _)| header_number(headers, remaining).unwrap_or(0) == 0)
+        .filter_map(|(_, reset)| header_number(headers, reset))
+        .max()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_quota_from_response_headers() {
+        let server = MockServer::start().await;
+        // Already passed, it is sent again right away
+        let reset = now() - 10;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(429)
+                    .insert_header("x-rate-limit-remaining", "0")
+                    .insert_header("x-rate-limit-reset", reset.to_string().as_str()),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(201)
+                    .insert_header("x-rate-limit-remaining", "0")
+                    .insert_header("x-rate-limit-reset", (now() + 60).to_string().as_str())
+                    // The tweet id holds 429, it is not a rate limit
+                    .set_body_string(r#"{"data": {"id": "1429"}}"#),
+            )
+            .mount(&server)
+            .await;
+        let dir = TempDir::new("rate-headers");
+        let limiter = RateLimiter::open(dir.path(), None).await;
+
+        let client = reqwest::Client::new();
+        let response = limiter
+            .send(Endpoint::Tweet, || client.post(server.uri()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        // The response said nothing is left until the reset
+        let exhausted =
+            tokio::time::timeout(Duration::from_millis(50), limiter.acquire(Endpoint::Tweet)).await;
+        assert!(exhausted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_retries_are_capped() {
+        let server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .respond_with(
+                ResponseTemplate::new(429)
+                    .insert_header("x-rate-limit-reset", (now() - 10).to_string().as_str()),
+            )
+            .expect(u64::from(MAX_RATE_LIMITED) + 1)
+            .mount(&server)
+            .await;
+        let dir = TempDir::new("rate-capped");
+        let limiter = RateLimiter::open(dir.path(), None).await;
+
+        let client = reqwest::Client::new();
+        let error = limiter
+            .send(Endpoint::Delete, || client.delete(server.uri()))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("still exceeded"));
+    }
+
+    #[test]
+    fn test_rate_limit_errors() {
+        assert!(is_rate_limited("HTTP status 429: Too Many Requests"));
+        assert!(!is_rate_limited("Your media IDs are invalid"));
+        assert_eq!(
+            reset_quoted("status 429, x-rate-limit-remaining: 0, x-rate-limit-reset: 1700000000"),
+            Some(1700000000)
+        );
+        assert_eq!(
+            reset_quoted("x-rate-limit-reset=1700000000 x-user-limit-24hour-reset=1700050000"),
+            Some(1700050000)
+        );
+        assert_eq!(reset_quoted("Too Many Requests"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_calls_are_made_again() {
+        let dir = TempDir::new("rate-calls");
+        let limiter = RateLimiter::open(dir.path(), None).await;
+        let limited = format!("429 Too Many Requests, x-rate-limit-reset: {}", now() - 10);
+
+        let mut calls = 0;
+        let result = limiter
+            .call(Endpoint::Tweet, || {
+                calls += 1;
+                let result = match calls {
+                    1 => Err(limited.clone().into()),
+                    _ => Ok("100"),
+                };
+                async move { result }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "100");
+
+        let mut calls = 0;
+        let error = limiter
+            .call(Endpoint::Delete, || {
+                calls += 1;
+                let error: Box<dyn Error + Send + Sync> = limited.clone().into();
+                async move { Err::<(), _>(error) }
+            })
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("still exceeded"));
+        assert_eq!(calls, MAX_RATE_LIMITED + 1);
+    }
+
+    #[tokio::test]
+    async fn test_daily_tweets_are_kept_across_runs() {
+        let dir = TempDir::new("rate-limits");
+
+        let limiter = RateLimiter::open(dir.path(), Some(2)).await;
+        limiter.acquire(Endpoint::Tweet).await;
+        limiter.acquire(Endpoint::Media).await;
+
+        let limiter = RateLimiter::open(dir.path(), Some(2)).await;
+        limiter.acquire(Endpoint::Tweet).await;
+        let exhausted =
+            tokio::time::timeout(Duration::from_millis(50), limiter.acquire(Endpoint::Tweet)).await;
+        assert!(exhausted.is_err());
+
+        limiter.limited(Endpoint::Media, Some(now() + 60)).await;
+        let limited =
+            tokio::time::timeout(Duration::from_millis(50), limiter.acquire(Endpoint::Media)).await;
+        assert!(limited.is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Maximum weighted length of a single tweet
+pub const MAX_TWEET_LENGTH: usize = 280;
+
+/// Weighted length of any URL, regardless of its real length
+pub const URL_LENGTH: usize = 23;
+
+/// Code point ranges that count as a single character, everything else (CJK, emoji, ...) counts
+/// as two
+const LIGHT_RANGES: [(u32, u32); 4] = [
+    (0x0000, 0x10FF),
+    (0x2000, 0x200D),
+    (0x2010, 0x201F),
+    (0x2032, 0x2037),
+];
+
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+
+/// Applies the Unicode normalization (NFC) Twitter uses before counting characters
+pub fn normalize(text: &str) -> String {
+    text.nfc().collect()
+}
+
+/// Computes the length of a text as counted by Twitter
+///
+/// The text is normalized first, URLs count as [`URL_LENGTH`], emoji sequences count as two and
+/// any other character counts as one or two according to its code point, e.g. `"日本"` has
+/// length 4.
+pub fn weighted_length(text: &str) -> usize {
+    segments(&normalize(text)).iter().map(|s| s.weight).sum()
+}
+
+/// Computes the length of a text as counted by Mastodon
+///
+/// URLs count as [`URL_LENGTH`] and any other character counts as one, e.g. `"日本"` has length
+/// 2.
+pub fn char_length(text: &str) -> usize {
+    let mut start = 0;
+    let mut total = 0;
+    for segment in segments(text) {
+        total += match segment.url {
+            true => URL_LENGTH,
+            false => text[start..segment.end].chars().count(),
+        };
+        start = segment.end;
+    }
+    total
+}
+
+/// Returns the byte index of the longest prefix of an already normalized `text` whose length,
+/// as counted by `length`, fits in `budget`. URLs and emoji sequences are never cut in the middle
+pub fn max_prefix<L: Fn(&str) -> usize>(text: &str, budget: usize, length: L) -> usize {
+    let mut total = 0;
+    let mut end = 0;
+    for segment in segments(text) {
+        total += length(&text[end..segment.end]);
+        if total > budget {
+            break;
+        }
+        end = segment.end;
+    }
+    end
+}
+
+/// A piece of text that is counted as a whole
+struct Segment {
+    end: usize,
+    weight: usize,
+    url: bool,
+}
+
+fn segments(text: &str) -> Vec<Segment> {
+    let mut segments = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let at_word_start =
+            start == 0 || text[..start].ends_with(|c: char| c.is_whitespace() || c == '(');
+        if at_word_start && is_url_start(&text[start..]) {
+            let end = text[start..]
+                .find(char::is_whitespace)
+                .map(|i| start + i)
+                .unwrap_or(text.len());
+            while chars.peek().map(|(i, _)| *i < end).unwrap_or(false) {
+                chars.next();
+            }
+            segments.push(Segment {
+                end,
+                weight: URL_LENGTH,
+                url: true,
+            });
+        } else if is_emoji(c) {
+            let mut end = start + c.len_utf8();
+            if is_regional_indicator(c) {
+                // Flags are made of two regional indicators
+                if let Some(&(i, next)) = chars.peek().filter(|(_, n)| is_regional_indicator(*n)) {
+                    end = i + next.len_utf8();
+                    chars.next();
+                }
+            }
+            while let Some(&(i, next)) = chars.peek() {
+                let joined = next == ZERO_WIDTH_JOINER || is_emoji_modifier(next);
+                let after_joiner = text[..i].ends_with(ZERO_WIDTH_JOINER) && is_emoji(next);
+                if !joined && !after_joiner {
+                    break;
+                }
+                end = i + next.len_utf8();
+                chars.next();
+            }
+            segments.push(Segment {
+                end,
+                weight: 2,
+                url: false,
+            });
+        } else {
+            segments.push(Segment {
+                end: start + c.len_utf8(),
+                weight: char_weight(c),
+                url: false,
+            });
+        }
+    }
+    segments
+}
+
+fn char_weight(c: char) -> usize {
+    let code = c as u32;
+    if LIGHT_RANGES
+        .iter()
+        .any(|(from, to)| code >= *from && code <= *to)
+    {
+        1
+    } else {
+        2
+    }
+}
+
+fn is_url_start(text: &str) -> bool {
+    let lower = text
+        .chars()
+        .take(8)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    // The prefix is ASCII, so it is safe to slice the text after it once it matched
+    let has_host = |prefix: &str| {
+        lower.starts_with(prefix)
+            && text.len() > prefix.len()
+            && !text[prefix.len()..].starts_with(char::is_whitespace)
+    };
+    has_host("https://") || has_host("http://") || has_host("www.")
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2B00..=0x2BFF)
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c as u32, 0x1F1E6..=0x1F1FF)
+}
+
+/// Characters that are rendered as part of the preceding emoji
+fn is_emoji_modifier(c: char) -> bool {
+    matches!(c as u32,
+        0xFE0E..=0xFE0F | 0x1F3FB..=0x1F3FF | 0x20E3 | 0xE0020..=0xE007F)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_weighted_length() {
+        assert_eq!(weighted_length(""), 0);
+        assert_eq!(weighted_length("Olá, mundo"), 10);
+        assert_eq!(weighted_length("“quoted” — dash"), 15);
+        assert_eq!(weighted_length("日本語"), 6);
+        assert_eq!(weighted_length("한국어"), 6);
+    }
+
+    #[test]
+    fn test_emoji_sequences_count_as_two() {
+        assert_eq!(weighted_length("😀"), 2);
+        assert_eq!(weighted_length("👍🏽"), 2);
+        assert_eq!(weighted_length("👨‍👩‍👧‍👦"), 2);
+        assert_eq!(weighted_length("❤️ok"), 4);
+        assert_eq!(weighted_length("🇧🇷🇵🇹"), 4);
+    }
+
+    #[test]
+    fn test_urls_count_as_23() {
+        assert_eq!(weighted_length("https://t.co"), URL_LENGTH);
+        assert_eq!(
+            weighted_length("read https://example.com/a/really/long/path?with=query now"),
+            5 + URL_LENGTH + 4
+        );
+        assert_eq!(weighted_length("www.example.com"), URL_LENGTH);
+        assert_eq!(
+            weighted_length("docs (https://example.com/a/long/path)"),
+            6 + URL_LENGTH
+        );
+        assert_eq!(weighted_length("https://"), 8);
+    }
+
+    #[test]
+    fn test_normalization() {
+        // "e" followed by a combining acute accent is normalized to a single "é"
+        assert_eq!(weighted_length("e\u{301}"), 1);
+        assert_eq!(normalize("e\u{301}"), "é");
+    }
+
+    #[test]
+    fn test_char_length() {
+        assert_eq!(char_length("日本語"), 3);
+        assert_eq!(char_length("👍🏽 ok"), 5);
+        assert_eq!(
+            char_length("read https://example.com/a/really/long/path?with=query now"),
+            5 + URL_LENGTH + 4
+        );
+    }
+
+    #[test]
+    fn test_max_prefix() {
+        assert_eq!(max_prefix("hello world", 5, weighted_length), 5);
+        assert_eq!(max_prefix("日本語", 5, weighted_length), "日本".len());
+        assert_eq!(max_prefix("日本語", 5, char_length), "日本語".len());
+        let text = "go https://example.com/path";
+        assert_eq!(max_prefix(text, 10, weighted_length), 3);
+        assert_eq!(max_prefix(text, 26, weighted_length), text.len());
+        assert_eq!(max_prefix(text, 26, char_length), text.len());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::twitter::text::{max_prefix, normalize};
+
+/// Splits a text into the parts of a tweet thread
+///
+/// Lengths are counted the way the destination does, e.g. with
+/// [`weighted_length`](crate::twitter::text::weighted_length) for Twitter. Texts that fit in
+/// `max_length` are returned untouched. Longer texts are split on sentence
+/// boundaries when possible, falling back to word boundaries and finally to a hard cut, and each
+/// part is suffixed with its position in the thread, e.g. ` 1/3`. A thread of more tweets than
+/// the text needs, e.g. to carry the media, ends with parts holding only their position.
+///
+/// # Arguments
+///  * `text` - The text to split
+///  * `max_length` - The maximum length of each part, including the numbering suffix
+///  * `min_parts` - The number of tweets of the thread, if the text needs fewer
+///  * `length` - The length of a text as counted by the destination
+pub fn split_text<L: Fn(&str) -> usize>(
+    text: &str,
+    max_length: usize,
+    min_parts: usize,
+    length: L,
+) -> Vec<String> {
+    let normalized = normalize(text);
+    let text = normalized.trim();
+    if length(text) <= max_length && min_parts <= 1 {
+        return vec![text.to_string()];
+    }
+
+    // The suffix length depends on the number of parts, retry while it keeps growing
+    let mut total = min_parts.max(2);
+    loop {
+        let budget = max_length.saturating_sub(length(&suffix(total, total)));
+        let mut parts = split_chunks(text, budget.max(1), &length);
+        if parts.len() <= total {
+            parts.resize(parts.len().max(min_parts), String::new());
+            let count = parts.len();
+            return parts
+                .into_iter()
+                .enumerate()
+                .map(|(i, part)| (part + &suffix(i + 1, count)).trim_start().to_string())
+                .collect();
+        }
+        total = parts.len();
+    }
+}
+
+fn suffix(index: usize, total: usize) -> String {
+    format!(" {}/{}", index, total)
+}
+
+/// Splits the text in chunks of at most `budget` length, without numbering
+fn split_chunks<L: Fn(&str) -> usize>(text: &str, budget: usize, length: &L) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut remaining = text.trim_start();
+    while !remaining.is_empty() {
+        if length(remaining) <= budget {
+            chunks.push(remaining.trim_end().to_string());
+            break;
+        }
+        let end = cut_point(remaining, budget, length);
+        chunks.push(remaining[..end].trim_end().to_string());
+        remaining = remaining[end..].trim_start();
+    }
+    chunks
+}
+
+/// Finds the byte index where a text longer than `budget` should be cut
+fn cut_point<L: Fn(&str) -> usize>(text: &str, budget: usize, length: &L) -> usize {
+    let limit = match max_prefix(text, budget, length) {
+        0 => text.chars().next().map(char::len_utf8).unwrap_or(0),
+        limit => limit,
+    };
+    let window = &text[..limit];
+
+    // Prefer the end of a sentence, as long as it does not produce a tiny chunk
+    let sentence_end = window
+        .char_indices()
+        .zip(window.chars().skip(1))
+        .filter(|((_, c), next)| {
+            (matches!(c, '.' | '!' | '?') && next.is_whitespace()) || *c == '\n'
+        })
+        .map(|((i, c), _)| i + c.len_utf8())
+        .last();
+    if let Some(end) = sentence_end.filter(|e| length(&window[..*e]) >= budget / 2) {
+        return end;
+    }
+
+    // Otherwise break at the last word boundary, or cut the word if there is none
+    match window.rfind(char::is_whitespace) {
+        Some(end) if end > 0 => end,
+        _ => limit,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::twitter::text::{char_length, weighted_length};
+
+    #[test]
+    fn test_short_text_is_not_split() {
+        assert_eq!(
+            split_text("Hello world", 280, 1, weighted_length),
+            vec!["Hello world"]
+        );
+        assert_eq!(split_text("", 280, 0, weighted_length), vec![""]);
+    }
+
+    #[test]
+    fn test_parts_for_media_are_numbered() {
+        assert_eq!(
+            split_text("Hello world", 280, 3, weighted_length),
+            vec!["Hello world 1/3", "2/3", "3/3"]
+        );
+        let text = "First sentence here. Second sentence here.";
+        assert_eq!(
+            split_text(text, 30, 3, weighted_length),
+            vec![
+                "First sentence here. 1/3",
+                "Second sentence here. 2/3",
+                "3/3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_on_sentences() {
+        let text = "First sentence here. Second sentence here. Third one.";
+        let parts = split_text(text, 30, 1, weighted_length);
+        assert_eq!(
+            parts,
+            vec![
+                "First sentence here. 1/3",
+                "Second sentence here. 2/3",
+                "Third one. 3/3"
+            ]
+        );
+        assert!(parts.iter().all(|p| weighted_length(p) <= 30));
+    }
+
+    #[test]
+    fn test_split_on_words_and_hard_cut() {
+        let text = "word ".repeat(100);
+        let parts = split_text(&text, 50, 1, weighted_length);
+        assert!(parts.iter().all(|p| weighted_length(p) <= 50));
+        assert!(parts.iter().all(|p| !p.contains("wo ")));
+
+        let parts = split_text(&"日".repeat(100), 20, 1, weighted_length);
+        assert!(parts.iter().all(|p| weighted_length(p) <= 20));
+        let joined: String = parts.iter().map(|p| p.split(' ').next().unwrap()).collect();
+        assert_eq!(joined, "日".repeat(100));
+    }
+
+    #[test]
+    fn test_split_as_the_destination_counts() {
+        let text = "日".repeat(100);
+        let parts = split_text(&text, 60, 1, char_length);
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|p| char_length(p) <= 60));
+        assert_eq!(split_text(&text, 60, 1, weighted_length).len(), 4);
+    }
+
+    #[test]
+    fn test_urls_are_not_cut() {
+        let url = "https://example.com/some/long/path/that/is/longer/than/the/limit";
+        let text = format!("{} {} {}", "a".repeat(20), url, "b".repeat(20));
+        let parts = split_text(&text, 30, 1, weighted_length);
+        assert!(parts.iter().any(|p| p.starts_with(url)));
+        assert!(parts.iter().all(|p| weighted_length(p) <= 30));
+    }
+
+    #[test]
+    fn test_suffix_grows_with_parts() {
+        let text = "abc ".repeat(300);
+        let parts = split_text(&text, 20, 1, weighted_length);
+        let total = parts.len();
+        assert!(total >= 10);
+        assert!(parts[total - 1].ends_with(&format!(" {}/{}", total, total)));
+        assert!(parts.iter().all(|p| weighted_length(p) <= 20));
+    }
+}
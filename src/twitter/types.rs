@@ -1,12 +1,18 @@
+use crate::twitter::text::{weighted_length, MAX_TWEET_LENGTH};
 use async_trait::async_trait;
+use mime_guess::Mime;
 use std::error::Error;
 use std::path::Path;
-use mime_guess::Mime;
 
 #[derive(Debug, Clone)]
 pub struct TwitterBuilder {
     media_ids: Vec<u64>,
     text: String,
+    reply_to: Option<String>,
+    quote: Option<String>,
+    /// The Telegram post and the part of its thread this is made of, the same each time it is
+    /// sent again
+    source: Option<String>,
 }
 
 impl TwitterBuilder {
@@ -14,6 +20,9 @@ impl TwitterBuilder {
         TwitterBuilder {
             media_ids: vec![],
             text: "".to_string(),
+            reply_to: None,
+            quote: None,
+            source: None,
         }
     }
     pub fn add_media(&mut self, media_id: u64) {
@@ -24,20 +33,144 @@ impl TwitterBuilder {
         self.text = text;
     }
 
+    pub fn set_reply_to(&mut self, tweet_id: Option<String>) {
+        self.reply_to = tweet_id;
+    }
+
+    pub fn set_quote(&mut self, tweet_id: Option<String>) {
+        self.quote = tweet_id;
+    }
+
+    /// Sets the part `index` of the thread of a Telegram post as the source, an edit of the
+    /// post is another source
+    pub fn set_source(&mut self, tg_id: i32, edit_date: Option<i64>, index: usize) {
+        self.source = Some(format!("{}-{}-{}", tg_id, edit_date.unwrap_or(0), index));
+    }
+
     pub fn media_ids(&self) -> &Vec<u64> {
         &self.media_ids
     }
     pub fn text(&self) -> String {
         self.text.clone()
     }
+    pub fn reply_to(&self) -> Option<&String> {
+        self.reply_to.as_ref()
+    }
+    pub fn quote(&self) -> Option<&String> {
+        self.quote.as_ref()
+    }
+    pub fn source(&self) -> Option<&String> {
+        self.source.as_ref()
+    }
+}
+
+/// Errors of the Twitter API that fail the same way if retried
+const FATAL_ERRORS: [&str; 9] = [
+    "Your media IDs are invalid",
+    "media type unrecognized",
+    "Tweet is too long",
+    "Status is a duplicate",
+    "Media processing failed",
+    // Mastodon
+    "Status is too long",
+    "Validation failed",
+    // Bluesky
+    "Post is too long",
+    "Blob is too large",
+];
+
+/// Whether a request that failed with this error may succeed if sent again
+pub fn is_retryable(error: &(dyn Error + Send + Sync)) -> bool {
+    let message = error.to_string();
+    !FATAL_ERRORS.iter().any(|fatal| message.contains(fatal))
 }
 
 #[async_trait]
 pub trait Postable: Sync + Send + 'static {
-    async fn upload_media(&mut self, file: &Path, media_type: &Mime) -> Result<u64, Box<dyn Error>>;
+    async fn upload_media(
+        &mut self,
+        file: &Path,
+        media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>>;
     async fn send(&mut self) -> Result<String, Box<dyn Error + Send + Sync>>;
+    async fn delete(&mut self, tweet_id: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
 }
 
 pub trait TwitterClient: Postable {
     fn new_builder(&mut self) -> &mut TwitterBuilder;
+
+    /// Maximum length of the text of a single post
+    fn max_length(&self) -> usize {
+        MAX_TWEET_LENGTH
+    }
+
+    /// Length of a text as counted against [`max_length`](TwitterClient::max_length)
+    fn length(&self, text: &str) -> usize {
+        weighted_length(text)
+    }
+}
+
+/// Records the posts sent and deleted instead of publishing them, for the tests of the stages
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct FakeClient {
+    builder: TwitterBuilder,
+    pub(crate) sent: std::sync::Arc<std::sync::Mutex<Vec<TwitterBuilder>>>,
+    pub(crate) deleted: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    /// The error the posts and the deletions fail with, if set
+    pub(crate) failure: std::sync::Arc<std::sync::Mutex<Option<String>>>,
+}
+
+#[cfg(test)]
+impl FakeClient {
+    pub(crate) fn new() -> Self {
+        FakeClient {
+            builder: TwitterBuilder::new(),
+            sent: Default::default(),
+            deleted: Default::default(),
+            failure: Default::default(),
+        }
+    }
+
+    pub(crate) fn texts(&self) -> Vec<String> {
+        self.sent.lock().unwrap().iter().map(|b| b.text()).collect()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Postable for FakeClient {
+    async fn upload_media(
+        &mut self,
+        _file: &Path,
+        _media_type: &Mime,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        Ok(1)
+    }
+
+    /// The tweets are numbered from 100 in the order they are sent
+    async fn send(&mut self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        if let Some(failure) = self.failure.lock().unwrap().clone() {
+            return Err(failure.into());
+        }
+        let mut sent = self.sent.lock().unwrap();
+        sent.push(self.builder.clone());
+        Ok((99 + sent.len()).to_string())
+    }
+
+    async fn delete(&mut self, tweet_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(failure) = self.failure.lock().unwrap().clone() {
+            return Err(failure.into());
+        }
+        self.deleted.lock().unwrap().push(tweet_id.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl TwitterClient for FakeClient {
+    fn new_builder(&mut self) -> &mut TwitterBuilder {
+        self.builder = TwitterBuilder::new();
+        &mut self.builder
+    }
 }
@@ -1,24 +1,37 @@
-use crate::twitter::types::TwitterClient;
-use crate::types::{Post, Processor, Runnable};
-use crate::Cfg;
+use crate::dead_letter::DeadLetters;
+use crate::journal::{Journal, Step};
+use crate::retry::RetryPolicy;
+use crate::twitter::types::{is_retryable, TwitterClient};
+use crate::types::{Post, Processor, Route, Runnable};
 use log::warn;
 use std::path::PathBuf;
-use critter::error::Error;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
 
 pub struct TwitterUploader<C> {
     client: C,
     data_dir: String,
+    retry: RetryPolicy,
+    dead_letters: DeadLetters,
+    journal: Journal,
     receiver: Option<Receiver<Post>>,
     sender: Option<Sender<Post>>,
 }
 
 impl<C: TwitterClient> TwitterUploader<C> {
-    pub fn new(client: C, cfg: &Cfg) -> Self {
+    pub fn new(
+        client: C,
+        route: &Route,
+        retry: RetryPolicy,
+        dead_letters: DeadLetters,
+        journal: Journal,
+    ) -> Self {
         TwitterUploader {
             client,
-            data_dir: cfg.data_dir.clone(),
+            data_dir: route.data_dir.clone(),
+            retry,
+            dead_letters,
+            journal,
             receiver: None,
             sender: None,
         }
@@ -45,11 +58,33 @@ impl<C: TwitterClient> Runnable for TwitterUploader<C> {
                     Some(mut msg) => {
                         let mut attach_failed = false;
                         let mut media_ids = vec![];
-                        for attachment in msg.attachments() {
+                        let resumed = self.journal.resume(&msg).await;
+                        let attachments = match resumed.filter(|e| e.step >= Step::Uploaded) {
+                            Some(entry) => {
+                                log::info!("Media of telegram post {} already uploaded", msg.id());
+                                media_ids = entry.media();
+                                vec![]
+                            }
+                            None => msg.attachments().clone(),
+                        };
+                        for attachment in &attachments {
                             let mut buf = PathBuf::from(&self.data_dir);
                             buf.push(attachment.path());
                             let media_type = attachment.mime();
-                            let result = self.client.upload_media(buf.as_path(), media_type).await;
+                            let what = format!("[Uploader] Upload of media {}", attachment.path());
+                            let mut attempt = 0;
+                            let result = loop {
+                                attempt += 1;
+                                match self.client.upload_media(buf.as_path(), media_type).await {
+                                    Err(e)
+                                        if is_retryable(&*e)
+                                            && self.retry.wait(attempt, &what, &e).await =>
+                                    {
+                                        continue
+                                    }
+                                    result => break result,
+                                }
+                            };
 
                             match result {
                                 Ok(id) => {
@@ -57,25 +92,39 @@ impl<C: TwitterClient> Runnable for TwitterUploader<C> {
                                         "Media {} successfully processed",
                                         attachment.path()
                                     );
-                                    media_ids.push(id);
+                                    media_ids.push((id, media_type.clone()));
                                 }
                                 Err(err) => {
-                                    warn!(
-                                        "[Uploader] Error uploading media {} : {:?}",
-                                        msg.id(),
-                                        err
-                                    );
-                                    if let Some(bad_media) = err.downcast_ref::<Error>() {
-                                        println!("The media is not supported by Twitter, this will no be retried {}", bad_media);
-                                    } else {
+                                    if is_retryable(&*err) {
+                                        warn!(
+                                            "[Uploader] Error uploading media {} : {:?}",
+                                            msg.id(),
+                                            err
+                                        );
+                                        self.dead_letters.add(msg.id(), "upload", &err).await;
                                         attach_failed = true;
+                                        break;
+                                    } else {
+                                        warn!(
+                                            "[Uploader] Media of {} is not supported by Twitter, it will not be retried: {}",
+                                            msg.id(),
+                                            err
+                                        );
                                     }
                                 }
                             }
                         }
                         if !attach_failed {
-                            for media in media_ids {
-                                msg.add_twitter_attachment(media);
+                            self.journal
+                                .record(&msg, Step::Uploaded, |entry| {
+                                    entry.media = media_ids
+                                        .iter()
+                                        .map(|(id, media_type)| (*id, media_type.to_string()))
+                                        .collect();
+                                })
+                                .await;
+                            for (media, media_type) in media_ids {
+                                msg.add_twitter_attachment(media, media_type);
                             }
                             self.sender.as_ref().unwrap().send(msg).await.expect("TODO");
                         }
@@ -1,58 +1,305 @@
+use crate::dead_letter::DeadLetters;
+use crate::journal::Journal;
+use crate::mapping::MappingStore;
 use crate::persistence::Persister;
+use crate::storage::Storage;
+use crate::telegram::deletions::DeletionScanner;
+use crate::telegram::dispatcher::UpdateDispatcher;
 use crate::telegram::downloader::TelegramDownloader;
 use crate::telegram::fetcher::TelegramGenerator;
-use crate::telegram::types::TelegramClient;
+use crate::telegram::formatter::TelegramFormatter;
+use crate::telegram::forwards::TelegramForwards;
+use crate::telegram::types::{TelegramClient, TelegramUpdate};
+use crate::transform::TextTransformer;
+use crate::twitter::deleter::TwitterDeleter;
+use crate::twitter::editor::TwitterEditor;
+use crate::twitter::gate::TwitterGate;
 use crate::twitter::poster::TwitterPoster;
 use crate::twitter::types::TwitterClient;
 use crate::twitter::uploader::TwitterUploader;
-use crate::types::Cfg;
+use crate::types::{Broadcast, Cfg, Post, Route, Target};
 use crate::types::{Processor, Runnable, Source};
+use std::collections::HashMap;
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+/// The chat and the mapping of every destination of every route, by route and destination name
+type MirroredPosts = HashMap<(String, String), (String, MappingStore)>;
+
 pub struct Twittergram<T, U> {
     config: Cfg,
     tg_client: U,
-    tw_client: T,
+    routes: Vec<(Route, Vec<(Target, T)>)>,
+}
+
+/// What a destination of a route needs to mirror the posts
+struct Branch<T> {
+    target: Target,
+    client: T,
+    mappings: MappingStore,
+    dead_letters: DeadLetters,
+    journal: Journal,
+    persister: Persister,
 }
 
 impl<T: TwitterClient + Clone, U: TelegramClient + Clone> Twittergram<T, U> {
-    pub fn new(config: Cfg, tg_client: U, tw_client: T) -> Self {
+    /// Mirrors each route to its destinations, each with its own client, all of them share the
+    /// Telegram client
+    pub fn new(config: Cfg, tg_client: U, routes: Vec<(Route, Vec<(Target, T)>)>) -> Self {
         Twittergram {
             config,
             tg_client,
-            tw_client,
+            routes,
         }
     }
 
     pub async fn run(self) -> Result<()> {
-        let mut persister = Persister::new(&self.config.data_dir).await;
-        log::info!("Last processed id: {}", persister.get_last_id());
+        let mut dispatcher = UpdateDispatcher::new(self.tg_client.clone());
+        let storage = Storage::open(&self.config);
+        // Opened up front, so forwards can be matched with the posts of the other routes
+        let mut mappings = HashMap::new();
+        for (route, targets) in &self.routes {
+            for (target, _) in targets {
+                let store = MappingStore::open(&storage, &target.data_dir, &target.key).await;
+                mappings.insert(
+                    (route.name.clone(), target.name.clone()),
+                    (route.chat_name.clone(), store),
+                );
+            }
+        }
+        let mut handles = vec![];
+        for (route, targets) in &self.routes {
+            let updates = if self.config.daemon {
+                Some(dispatcher.subscribe(&route.chat_name))
+            } else {
+                None
+            };
+            handles.extend(
+                self.start_route(&storage, route, targets, &mappings, updates)
+                    .await,
+            );
+        }
+        if self.config.daemon {
+            handles.push(dispatcher.run());
+        }
+
+        for handle in handles {
+            let _ = handle.await;
+        }
+
+        log::info!("End processing");
+        Ok(())
+    }
+
+    /// Opens the progress of a destination of the route
+    async fn open_branch(
+        &self,
+        storage: &Storage,
+        route: &Route,
+        target: &Target,
+        client: T,
+        mirrored: &MirroredPosts,
+    ) -> Branch<T> {
+        let mappings = mirrored[&(route.name.clone(), target.name.clone())]
+            .1
+            .clone();
+        let dead_letters = DeadLetters::open(storage, &target.data_dir, &target.key).await;
+        let journal = Journal::open(storage, &target.data_dir).await;
+        let persister = Persister::new(
+            storage,
+            &target.data_dir,
+            &target.key,
+            mappings.clone(),
+            self.config.retry.clone(),
+            dead_letters.clone(),
+            journal.clone(),
+        )
+        .await;
+        log::info!(
+            "[{}] Last processed id on {}: {}",
+            route.name,
+            target.name,
+            persister.get_last_id()
+        );
+        Branch {
+            target: target.clone(),
+            client,
+            mappings,
+            dead_letters,
+            journal,
+            persister,
+        }
+    }
+
+    /// Starts the pipeline of a route: the posts are fetched and downloaded once, then each
+    /// destination uploads and posts them on its own
+    async fn start_route(
+        &self,
+        storage: &Storage,
+        route: &Route,
+        targets: &[(Target, T)],
+        mirrored: &MirroredPosts,
+        updates: Option<Receiver<TelegramUpdate<U::M>>>,
+    ) -> Vec<JoinHandle<()>> {
+        let mut branches = vec![];
+        for (target, client) in targets {
+            branches.push(
+                self.open_branch(storage, route, target, client.clone(), mirrored)
+                    .await,
+            );
+        }
+        // The destination furthest behind sets where to go on from, the others skip what they
+        // already mirrored
+        let last_id = branches
+            .iter()
+            .map(|b| b.persister.get_last_id())
+            .min()
+            .expect("Route without destination");
+        let retry = &self.config.retry;
 
+        // The first destination records the progress of the shared stages
+        let first = &branches[0];
         let mut generator = TelegramGenerator::new(
             self.tg_client.clone(),
             &self.config,
-            persister.get_last_id(),
+            route,
+            last_id,
+            first.mappings.clone(),
+            first.dead_letters.clone(),
+            first.journal.clone(),
+            updates,
         );
-        let mut downloader = TelegramDownloader::new(self.tg_client.clone(), &self.config);
-        let mut twitter_uploader = TwitterUploader::new(self.tw_client.clone(), &self.config);
-        let mut twitter_poster = TwitterPoster::new(self.tw_client.clone());
-
+        for branch in &branches[1..] {
+            generator.resume_for(branch.journal.clone(), branch.dead_letters.clone());
+        }
+        let mut formatter = TelegramFormatter::new(&self.config.formatting);
+        let mut downloader = TelegramDownloader::new(
+            self.tg_client.clone(),
+            route,
+            retry.clone(),
+            first.dead_letters.clone(),
+            first.journal.clone(),
+        );
+        let mut broadcast = Broadcast::new();
         generator
-            .drain_to(&mut downloader)
-            .connect_to(&mut twitter_uploader)
-            .connect_to(&mut twitter_poster)
-            .sink_at(&mut persister);
+            .drain_to(&mut formatter)
+            .connect_to(&mut downloader)
+            .sink_at(&mut broadcast);
 
-        let _ = tokio::join!(
+        let mut handles = vec![];
+        let mut deletions: Broadcast<i32> = Broadcast::new();
+        for branch in branches {
+            handles.extend(self.start_branch(
+                route,
+                branch,
+                &mut broadcast,
+                &mut deletions,
+                mirrored,
+            ));
+        }
+        if self.config.sync.deletions && self.config.daemon {
+            // The generator gets the deletions along with the other updates
+            generator.sink_at(&mut deletions);
+            handles.push(deletions.run());
+        }
+
+        handles.extend([
             generator.run(),
+            formatter.run(),
             downloader.run(),
-            twitter_uploader.run(),
-            twitter_poster.run(),
-            persister.run()
+            broadcast.run(),
+        ]);
+        handles
+    }
+
+    /// Starts the stages of a destination of the route
+    fn start_branch(
+        &self,
+        route: &Route,
+        branch: Branch<T>,
+        broadcast: &mut Broadcast<Post>,
+        deletions: &mut Broadcast<i32>,
+        mirrored: &MirroredPosts,
+    ) -> Vec<JoinHandle<()>> {
+        let Branch {
+            target,
+            client,
+            mappings,
+            dead_letters,
+            journal,
+            mut persister,
+        } = branch;
+        let retry = &self.config.retry;
+        let mut gate = TwitterGate::new(
+            &target.name,
+            mappings.clone(),
+            dead_letters.clone(),
+            journal.clone(),
+        );
+        let mut editor =
+            TwitterEditor::new(client.clone(), mappings.clone(), self.config.sync.edits);
+        let mut uploader = TwitterUploader::new(
+            client.clone(),
+            route,
+            retry.clone(),
+            dead_letters.clone(),
+            journal.clone(),
+        );
+        // Only posts of the same destination can be quoted
+        let quotable = mirrored
+            .iter()
+            .filter(|((_, name), _)| *name == target.name)
+            .map(|(_, mapping)| mapping.clone())
+            .collect();
+        let mut forwards = TelegramForwards::new(self.tg_client.clone(), route, quotable);
+        let mut transformer = TextTransformer::new(route);
+        let mut poster = TwitterPoster::new(
+            client.clone(),
+            mappings.clone(),
+            retry.clone(),
+            dead_letters,
+            journal,
         );
 
-        log::info!("End processing");
-        Ok(())
+        broadcast
+            .branch(&mut gate)
+            .connect_to(&mut editor)
+            .connect_to(&mut uploader)
+            .connect_to(&mut forwards)
+            .connect_to(&mut transformer)
+            .connect_to(&mut poster)
+            .sink_at(&mut persister);
+
+        let mut handles = vec![];
+        if self.config.sync.deletions {
+            // Catches up with the messages deleted while it was not running
+            let mut deleter = TwitterDeleter::new(client.clone(), mappings.clone());
+            let mut scanner = DeletionScanner::new(
+                self.tg_client.clone(),
+                &self.config,
+                route,
+                mappings.clone(),
+            );
+            scanner.sink_at(&mut deleter);
+            handles.extend([scanner.run(), deleter.run()]);
+            if self.config.daemon {
+                let mut deleter = TwitterDeleter::new(client, mappings);
+                deletions.sink_at(&mut deleter);
+                handles.push(deleter.run());
+            }
+        }
+
+        handles.extend([
+            gate.run(),
+            editor.run(),
+            uploader.run(),
+            forwards.run(),
+            transformer.run(),
+            poster.run(),
+            persister.run(),
+        ]);
+        handles
     }
 }
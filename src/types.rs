@@ -1,9 +1,14 @@
-use crate::telegram::types::TelegramMessage;
+use crate::filter::FilterRule;
+use crate::retry::RetryPolicy;
+use crate::telegram::types::{Entity, ForwardOrigin, TelegramMessage};
+use crate::transform::TransformConfig;
 use crate::{mime, APPLICATION_OCTET_STREAM, TEXT_VCARD};
 use grammers_client::types::Media;
 use grammers_client::types::Media::{Contact, Document, Photo, Sticker};
 use mime_guess::Mime;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
@@ -12,45 +17,391 @@ use tokio::task::JoinHandle;
 pub struct Cfg {
     pub(crate) data_dir: String,
     pub(crate) max_messages: i32,
+    #[serde(default)]
+    pub(crate) daemon: bool,
     pub(crate) telegram: TelegramConfig,
-    pub(crate) twitter: TwitterConfig,
+    pub(crate) twitter: Option<TwitterConfig>,
+    pub(crate) mastodon: Option<MastodonConfig>,
+    pub(crate) bluesky: Option<BlueskyConfig>,
+    #[serde(default)]
+    pub(crate) sync: SyncConfig,
+    #[serde(default)]
+    pub(crate) retry: RetryPolicy,
+    #[serde(default)]
+    pub(crate) storage: StorageKind,
+    #[serde(default)]
+    pub(crate) formatting: FormattingConfig,
+    /// Applied to the text of the posts of the single route, in order
+    #[serde(default)]
+    pub(crate) transforms: Vec<TransformConfig>,
+    /// Which posts of the single route are mirrored
+    #[serde(default)]
+    pub(crate) filters: Vec<FilterRule>,
+    /// How the forwarded posts of the single route are mirrored
+    #[serde(default)]
+    pub(crate) forwards: ForwardPolicy,
+    #[serde(default)]
+    pub(crate) routes: Vec<Route>,
+    /// Set with `--dry-run`
+    #[serde(skip)]
+    pub(crate) dry_run: Option<DryRunConfig>,
+}
+
+/// Writes what would be posted instead of posting it, leaving the progress untouched
+#[derive(Debug, Clone)]
+pub struct DryRunConfig {
+    /// The JSON lines file the posts are written to, stdout when `None`
+    pub(crate) file: Option<String>,
+    /// Saves the last message processed anyway, so the next run goes on after these posts
+    pub(crate) save_state: bool,
+}
+
+impl Cfg {
+    /// The routes to mirror. Without `[[routes]]`, a single route is made of `telegram.chat_name`
+    /// and the destinations of the top level, `[twitter]`, `[mastodon]` and `[bluesky]`, keeping
+    /// its state in the data dir itself
+    pub fn routes(&self) -> Vec<Route> {
+        if self.routes.is_empty() {
+            let chat_name = self
+                .telegram
+                .chat_name
+                .clone()
+                .expect("Missing telegram.chat_name");
+            let route = Route {
+                name: chat_name.clone(),
+                chat_name,
+                ignore: default_ignore(),
+                transforms: self.transforms.clone(),
+                filters: self.filters.clone(),
+                forwards: self.forwards,
+                twitter: self.twitter.clone(),
+                mastodon: self.mastodon.clone(),
+                bluesky: self.bluesky.clone(),
+                data_dir: self.data_dir.clone(),
+            };
+            route.check_destination();
+            return vec![route];
+        }
+
+        let mut names = HashSet::new();
+        self.routes
+            .iter()
+            .map(|route| {
+                // The name is the folder of the route in the data dir
+                let valid = !route.name.is_empty()
+                    && route
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if !valid {
+                    panic!(
+                        "Invalid route name {:?}, only letters, digits, _ and - are allowed",
+                        route.name
+                    );
+                }
+                if !names.insert(&route.name) {
+                    panic!("Duplicated route {}", route.name);
+                }
+                route.check_destination();
+                let mut data_dir = PathBuf::from(&self.data_dir);
+                data_dir.push(&route.name);
+                Route {
+                    data_dir: data_dir.to_str().expect("Invalid data dir").to_string(),
+                    ..route.clone()
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TelegramConfig {
     pub(crate) api_id: i32,
     pub(crate) api_hash: String,
+    pub(crate) chat_name: Option<String>,
+    /// Longest FLOOD_WAIT waited out before giving up on a request, in seconds
+    #[serde(default = "default_max_flood_wait")]
+    pub(crate) max_flood_wait: u64,
+}
+
+fn default_max_flood_wait() -> u64 {
+    300
+}
+
+/// Mirrors a Telegram chat to Twitter, Mastodon and Bluesky accounts, at least one of them
+#[derive(Deserialize, Debug, Clone)]
+pub struct Route {
+    pub(crate) name: String,
     pub(crate) chat_name: String,
+    /// Posts with this keyword are not mirrored
+    #[serde(default = "default_ignore")]
+    pub(crate) ignore: String,
+    /// Applied to the text of the posts, in order
+    #[serde(default)]
+    pub(crate) transforms: Vec<TransformConfig>,
+    /// Which posts are mirrored, checked after `ignore`
+    #[serde(default)]
+    pub(crate) filters: Vec<FilterRule>,
+    #[serde(default)]
+    pub(crate) forwards: ForwardPolicy,
+    pub(crate) twitter: Option<TwitterConfig>,
+    pub(crate) mastodon: Option<MastodonConfig>,
+    pub(crate) bluesky: Option<BlueskyConfig>,
+    /// Where the state and media of the route are kept
+    #[serde(skip)]
+    pub(crate) data_dir: String,
 }
 
-#[derive(Deserialize, Debug)]
+impl Route {
+    /// Panics unless the route has a destination
+    fn check_destination(&self) {
+        if self.targets().is_empty() {
+            panic!(
+                "Route {} has no [twitter], [mastodon] or [bluesky]",
+                self.name
+            );
+        }
+    }
+
+    /// The destinations of the route, each one keeps track of what was mirrored to it in a
+    /// folder named after it in the data dir of the route, whichever other destinations are
+    /// configured
+    pub fn targets(&self) -> Vec<Target> {
+        let destinations = [
+            self.twitter.clone().map(DestinationConfig::Twitter),
+            self.mastodon.clone().map(DestinationConfig::Mastodon),
+            self.bluesky.clone().map(DestinationConfig::Bluesky),
+        ];
+        destinations
+            .into_iter()
+            .flatten()
+            .map(|config| {
+                let name = config.name();
+                let mut data_dir = PathBuf::from(&self.data_dir);
+                data_dir.push(name);
+                Target {
+                    name: name.to_string(),
+                    data_dir: data_dir.to_str().expect("Invalid data dir").to_string(),
+                    key: format!("{}/{}", self.name, name),
+                    config,
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DestinationConfig {
+    Twitter(TwitterConfig),
+    Mastodon(MastodonConfig),
+    Bluesky(BlueskyConfig),
+}
+
+impl DestinationConfig {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DestinationConfig::Twitter(_) => "twitter",
+            DestinationConfig::Mastodon(_) => "mastodon",
+            DestinationConfig::Bluesky(_) => "bluesky",
+        }
+    }
+}
+
+/// A destination of a route, with where what was mirrored to it is kept
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub(crate) name: String,
+    pub(crate) config: DestinationConfig,
+    pub(crate) data_dir: String,
+    /// Identifies the progress of the destination in the SQLite database, made of the names of
+    /// the route and of the destination so routes mirroring the same chat don't share it
+    pub(crate) key: String,
+}
+
+fn default_ignore() -> String {
+    "#tgonly".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct TwitterConfig {
     pub(crate) api_key: String,
     pub(crate) api_secret: String,
     pub(crate) access_token: String,
     pub(crate) access_token_secret: String,
+    /// Number of tweets the account may send in 24 hours, if it is capped
+    #[serde(default)]
+    pub(crate) daily_tweets: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MastodonConfig {
+    /// The URL of the instance, e.g. `"https://mastodon.social"`
+    pub(crate) instance: String,
+    /// A token of an application of the account with the `write:statuses` and `write:media`
+    /// scopes
+    pub(crate) access_token: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BlueskyConfig {
+    /// The handle or email of the account
+    pub(crate) identifier: String,
+    /// An app password of the account (Settings > App passwords), not its password
+    pub(crate) app_password: String,
+    /// The PDS hosting the account
+    #[serde(default = "default_bluesky_service")]
+    pub(crate) service: String,
+}
+
+fn default_bluesky_service() -> String {
+    "https://bsky.social".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SyncConfig {
+    pub(crate) deletions: bool,
+    pub(crate) edits: EditPolicy,
+    pub(crate) window: usize,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            deletions: false,
+            edits: EditPolicy::Ignore,
+            window: 100,
+        }
+    }
+}
+
+/// How the formatting of the Telegram posts is rendered
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FormattingConfig {
+    pub(crate) links: LinkStyle,
+    /// Write bold, italic and code with Unicode styled letters
+    pub(crate) styles: bool,
+    /// Keep the text of spoilers instead of hiding it
+    pub(crate) show_spoilers: bool,
+    /// The Twitter handle of Telegram users, by username or by user id
+    pub(crate) mentions: HashMap<String, String>,
+}
+
+impl Default for FormattingConfig {
+    fn default() -> Self {
+        FormattingConfig {
+            links: LinkStyle::Inline,
+            styles: false,
+            show_spoilers: false,
+            mentions: HashMap::new(),
+        }
+    }
+}
+
+/// Where the URL of a text link goes
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkStyle {
+    /// After the text of the link, in parentheses
+    Inline,
+    /// At the end of the post
+    Append,
+}
+
+/// Where the progress of the routes is kept
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Files in the data dir of each route
+    #[default]
+    Files,
+    /// A SQLite database in the data dir, requires the `sqlite` feature
+    Sqlite,
+}
+
+/// How posts forwarded from another chat are mirrored
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardPolicy {
+    /// Tweet them as if the chat wrote them
+    #[default]
+    Keep,
+    /// Don't mirror them
+    Skip,
+    /// Tweet them with a line crediting the original chat
+    Attribute,
+    /// Quote the tweet of the original post when its chat is mirrored as well, credit it
+    /// otherwise
+    Quote,
+}
+
+/// What to do when an already mirrored Telegram post is edited
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EditPolicy {
+    /// Keep the tweets as they are
+    Ignore,
+    /// Delete the tweets and post the edited version
+    Repost,
+    /// Reply to the tweets with the edited text
+    Correct,
 }
 
 #[derive(Clone, Debug)]
 pub struct Post {
     id: i32,
     text: String,
+    /// The text as published on Telegram, before any change made on the way to Twitter
+    source_text: String,
+    reply_to: Option<i32>,
+    /// Unix timestamp of the publication
+    date: i64,
+    /// Signature of the author of a channel post
+    author: Option<String>,
+    forward: Option<ForwardOrigin>,
+    /// The formatting of the text as published on Telegram
+    entities: Vec<Entity>,
+    /// The tweet to quote in the first tweet
+    quote: Option<String>,
+    edit_date: Option<i64>,
+    edited: bool,
+    /// The edit is posted again in place of the tweets mirrored before, which were deleted
+    reposted: bool,
     tg_attachments: Vec<Attachment>,
-    tw_attachments: Vec<u64>,
+    /// The number of media as published on Telegram, before any is dropped on the way to Twitter
+    source_attachments: usize,
+    tw_attachments: Vec<(u64, Mime)>,
+    tweet_ids: Vec<String>,
 }
 
 impl Post {
     pub fn new(id: i32, text: String) -> Post {
         Post {
             id,
+            source_text: text.clone(),
             text,
+            reply_to: None,
+            date: 0,
+            author: None,
+            forward: None,
+            entities: vec![],
+            quote: None,
+            edit_date: None,
+            edited: false,
+            reposted: false,
             tg_attachments: vec![],
+            source_attachments: 0,
             tw_attachments: vec![],
+            tweet_ids: vec![],
         }
     }
 
     pub(crate) fn from_message<M: TelegramMessage>(msg: &M) -> Post {
         let mut post = Post::new(msg.id(), msg.text().to_string());
+        post.set_origin(msg);
+        post.set_reply_to(msg.reply_to_message_id());
+        post.set_edit_date(msg.edit_date());
         if let Some(media) = msg.media() {
             post.add_tg_attachment(Attachment::new(media));
         }
@@ -65,14 +416,123 @@ impl Post {
         &self.text
     }
 
+    pub fn set_text(&mut self, text: String) {
+        self.text = text;
+    }
+
+    /// The id of the Telegram message this post replies to
+    pub fn reply_to(&self) -> Option<i32> {
+        self.reply_to
+    }
+
+    pub fn set_reply_to(&mut self, reply_to: Option<i32>) {
+        self.reply_to = reply_to;
+    }
+
+    /// Copies when, by whom and from where the message was published
+    pub(crate) fn set_origin<M: TelegramMessage>(&mut self, msg: &M) {
+        self.set_date(msg.date());
+        self.author = msg.post_author().map(str::to_string);
+        self.set_forward(msg.forward());
+        self.set_entities(msg.entities());
+    }
+
+    pub fn date(&self) -> i64 {
+        self.date
+    }
+
+    pub fn set_date(&mut self, date: i64) {
+        self.date = date;
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    pub fn is_forwarded(&self) -> bool {
+        self.forward.is_some()
+    }
+
+    /// Where the post was first published, if it was forwarded
+    pub fn forward(&self) -> Option<&ForwardOrigin> {
+        self.forward.as_ref()
+    }
+
+    pub fn set_forward(&mut self, forward: Option<ForwardOrigin>) {
+        self.forward = forward;
+    }
+
+    pub fn entities(&self) -> &Vec<Entity> {
+        &self.entities
+    }
+
+    pub fn set_entities(&mut self, entities: Vec<Entity>) {
+        self.entities = entities;
+    }
+
+    pub fn quote(&self) -> Option<&String> {
+        self.quote.as_ref()
+    }
+
+    pub fn set_quote(&mut self, tweet_id: Option<String>) {
+        self.quote = tweet_id;
+    }
+
+    pub fn edit_date(&self) -> Option<i64> {
+        self.edit_date
+    }
+
+    pub fn set_edit_date(&mut self, edit_date: Option<i64>) {
+        self.edit_date = edit_date;
+    }
+
+    /// Whether this is the edited version of an already mirrored post
+    pub fn is_edit(&self) -> bool {
+        self.edited
+    }
+
+    pub fn set_edited(&mut self, edited: bool) {
+        self.edited = edited;
+    }
+
+    pub fn is_repost(&self) -> bool {
+        self.reposted
+    }
+
+    pub fn set_reposted(&mut self, reposted: bool) {
+        self.reposted = reposted;
+    }
+
+    /// A stable hash of the content as published on Telegram, used to tell whether an edit
+    /// changed anything
+    pub fn content_hash(&self) -> u64 {
+        let count = self.source_attachments as u64;
+        crate::util::stable_hash(
+            self.source_text
+                .as_bytes()
+                .iter()
+                .chain(&count.to_le_bytes()),
+        )
+    }
+
     pub fn attachments(&self) -> &Vec<Attachment> {
         &self.tg_attachments
     }
 
-    pub fn tw_attachments(&self) -> &Vec<u64> {
+    /// The ids of the media uploaded to Twitter
+    pub fn tw_attachments(&self) -> Vec<u64> {
+        self.tw_attachments.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// The media uploaded to Twitter, with their type
+    pub fn tw_media(&self) -> &Vec<(u64, Mime)> {
         &self.tw_attachments
     }
 
+    pub fn tweet_ids(&self) -> &Vec<String> {
+        &self.tweet_ids
+    }
+
     fn get_suffix(mime: &Mime) -> String {
         let extension = mime_guess::get_mime_extensions(mime)
             .map(|o| o[0])
@@ -84,21 +544,25 @@ impl Post {
         }
     }
 
-    pub fn add_twitter_attachment(&mut self, attachment: u64) {
-        self.tw_attachments.push(attachment);
+    pub fn add_twitter_attachment(&mut self, attachment: u64, media_type: Mime) {
+        self.tw_attachments.push((attachment, media_type));
+    }
+
+    pub fn set_tweet_ids(&mut self, tweet_ids: Vec<String>) {
+        self.tweet_ids = tweet_ids;
+    }
+
+    pub fn clear_attachments(&mut self) {
+        self.tg_attachments.clear();
     }
 
     pub fn add_tg_attachment(&mut self, mut attachment: Attachment) {
         let len = self.tg_attachments.len();
+        self.source_attachments += 1;
         let suffix = Post::get_suffix(&attachment.mime);
         attachment.path = format!("message-{}_{}{}", self.id(), len, suffix);
         self.tg_attachments.push(attachment)
     }
-
-    pub fn validate(&self, ignore: &str) -> bool {
-        let empty = self.text.is_empty() && self.tg_attachments.is_empty();
-        !empty && !self.text.contains(ignore)
-    }
 }
 
 #[derive(Clone, Debug)]
@@ -160,6 +624,12 @@ pub trait Source<A>: Runnable {
         processor.set_input(receiver);
         processor
     }
+
+    fn sink_at<S: Sink<A>>(&mut self, sink: &mut S) {
+        let (sender, receiver): (Sender<A>, Receiver<A>) = mpsc::channel(1000);
+        sink.set_input(receiver);
+        self.set_output(sender);
+    }
 }
 
 pub trait Sink<X>: Runnable {
@@ -181,3 +651,161 @@ pub trait Processor<A, B>: Runnable {
         self.set_output(sender);
     }
 }
+
+/// Sends a copy of each item to several branches of the pipeline, e.g. to post to several
+/// destinations in parallel. A branch that stopped is left out, and a slow one only holds the
+/// others back once its channel is full
+pub struct Broadcast<A> {
+    receiver: Option<Receiver<A>>,
+    senders: Vec<Sender<A>>,
+}
+
+impl<A> Broadcast<A> {
+    pub fn new() -> Self {
+        Broadcast {
+            receiver: None,
+            senders: vec![],
+        }
+    }
+
+    /// Adds a branch starting with the processor
+    pub fn branch<'a, B, P: Processor<A, B>>(&mut self, processor: &'a mut P) -> &'a mut P {
+        let (sender, receiver): (Sender<A>, Receiver<A>) = mpsc::channel(1000);
+        self.senders.push(sender);
+        processor.set_input(receiver);
+        processor
+    }
+
+    /// Adds a branch made of the sink alone
+    pub fn sink_at<S: Sink<A>>(&mut self, sink: &mut S) {
+        let (sender, receiver): (Sender<A>, Receiver<A>) = mpsc::channel(1000);
+        self.senders.push(sender);
+        sink.set_input(receiver);
+    }
+}
+
+impl<A> Default for Broadcast<A> {
+    fn default() -> Self {
+        Broadcast::new()
+    }
+}
+
+impl<A: Clone + Send + 'static> Sink<A> for Broadcast<A> {
+    fn set_input(&mut self, receiver: Receiver<A>) {
+        self.receiver = Some(receiver);
+    }
+}
+
+impl<A: Clone + Send + 'static> Runnable for Broadcast<A> {
+    fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(item) = self.receiver.as_mut().unwrap().recv().await {
+                let mut open = vec![];
+                for sender in self.senders.drain(..) {
+                    if sender.send(item.clone()).await.is_ok() {
+                        open.push(sender);
+                    } else {
+                        log::warn!("[Broadcast] A branch stopped, it no longer gets items");
+                    }
+                }
+                self.senders = open;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Collect {
+        receiver: Option<Receiver<i32>>,
+        items: Sender<Vec<i32>>,
+    }
+
+    impl Sink<i32> for Collect {
+        fn set_input(&mut self, receiver: Receiver<i32>) {
+            self.receiver = Some(receiver);
+        }
+    }
+
+    impl Runnable for Collect {
+        fn run(mut self) -> JoinHandle<()> {
+            tokio::spawn(async move {
+                let mut items = vec![];
+                while let Some(item) = self.receiver.as_mut().unwrap().recv().await {
+                    items.push(item);
+                }
+                self.items.send(items).await.unwrap();
+            })
+        }
+    }
+
+    fn config(route: &str) -> Cfg {
+        let config = format!(
+            r#"
+            data_dir = "/data"
+            max_messages = 10
+            [telegram]
+            api_id = 1
+            api_hash = "hash"
+            [[routes]]
+            name = "{}"
+            chat_name = "news"
+            [routes.mastodon]
+            instance = "https://example.social"
+            access_token = "token"
+            "#,
+            route
+        );
+        toml::from_str(&config).unwrap()
+    }
+
+    #[test]
+    fn test_route_data_dir() {
+        let routes = config("news-2_fr").routes();
+        assert_eq!(routes[0].data_dir, "/data/news-2_fr");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid route name")]
+    fn test_route_name_stays_in_data_dir() {
+        config("../news").routes();
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_to_every_branch() {
+        let (items, mut collected) = mpsc::channel(2);
+        let mut broadcast = Broadcast::new();
+        let mut first = Collect {
+            receiver: None,
+            items: items.clone(),
+        };
+        let mut second = Collect {
+            receiver: None,
+            items,
+        };
+        let mut stopped = Collect {
+            receiver: None,
+            items: mpsc::channel(1).0,
+        };
+        broadcast.sink_at(&mut first);
+        broadcast.sink_at(&mut stopped);
+        broadcast.sink_at(&mut second);
+        // A branch that stopped does not keep the others from getting the items
+        drop(stopped);
+
+        let (input, receiver) = mpsc::channel(10);
+        broadcast.set_input(receiver);
+        let handles = [broadcast.run(), first.run(), second.run()];
+        for i in 1..=3 {
+            input.send(i).await.unwrap();
+        }
+        drop(input);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(collected.recv().await.unwrap(), vec![1, 2, 3]);
+        assert_eq!(collected.recv().await.unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -27,6 +27,50 @@ pub fn read_input<T: FromStr>(name: String, input: &mut impl BufRead) -> T {
     return T::from_str(user_input.trim()).ok().unwrap();
 }
 
+/// FNV-1a hash of the bytes, the hashers in std are not guaranteed to be stable across releases
+pub fn stable_hash<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A directory of its own in the temp dir for a test, removed with its content when dropped
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "twittergram-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).expect("Error creating temp dir");
+        TempDir(path)
+    }
+
+    /// The path of the directory, the way data dirs are given
+    pub(crate) fn path(&self) -> &str {
+        self.0.to_str().expect("Temp dir path")
+    }
+
+    /// The path of a file in the directory
+    pub(crate) fn join(&self, name: &str) -> std::path::PathBuf {
+        self.0.join(name)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;